@@ -0,0 +1,94 @@
+use {
+    crate::*,
+    serde::Deserialize,
+    std::sync::Arc,
+};
+
+/// Configuration of the admin HTTP server
+#[derive(Debug, Clone, Deserialize)]
+pub struct AdminConf {
+    #[serde(default = "AdminConf::default_address")]
+    pub address: String,
+}
+
+impl AdminConf {
+    pub fn default_address() -> String {
+        "127.0.0.1:9798".to_owned()
+    }
+}
+
+/// decode the %XX sequences of an URL path
+fn percent_decode(s: &str) -> String {
+    let bytes = s.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        if bytes[i] == b'%' && i + 2 < bytes.len() {
+            let hex = std::str::from_utf8(&bytes[i + 1..i + 3]).unwrap_or_default();
+            if let Ok(b) = u8::from_str_radix(hex, 16) {
+                decoded.push(b);
+                i += 3;
+                continue;
+            }
+        }
+        decoded.push(bytes[i]);
+        i += 1;
+    }
+    String::from_utf8_lossy(&decoded).to_string()
+}
+
+fn json<T: serde::Serialize>(value: &T) -> HttpResponse {
+    HttpResponse::ok(
+        "application/json",
+        serde_json::to_string_pretty(value).unwrap(),
+    )
+}
+
+async fn handle(runner: Arc<Runner>, request: HttpRequest) -> HttpResponse {
+    let method = request.method.as_str();
+    let path = percent_decode(&request.path);
+    if path == "/reload" {
+        if method != "POST" {
+            return HttpResponse::text(405, "use POST");
+        }
+        return match runner.reload().await {
+            Ok(()) => HttpResponse::text(200, "configuration reloaded"),
+            Err(e) => HttpResponse::text(400, &format!("configuration not reloaded: {}", e)),
+        };
+    }
+    if path == "/watchers" {
+        let statuses: Vec<WatcherStatus> = runner.watchers().await.iter()
+            .map(|watcher| watcher.status())
+            .collect();
+        return json(&statuses);
+    }
+    let Some(watcher_path) = path.strip_prefix("/watchers/") else {
+        return HttpResponse::not_found();
+    };
+    // the input queue may contain slashes, the action is at the end
+    let (input_queue, control) = match watcher_path.rsplit_once('/') {
+        Some((queue, "pause")) => (queue, Some(WatcherControl::Pause)),
+        Some((queue, "resume")) => (queue, Some(WatcherControl::Run)),
+        Some((queue, "drain")) => (queue, Some(WatcherControl::Drain)),
+        _ => (watcher_path, None),
+    };
+    let Some(watcher) = runner.watcher(input_queue).await else {
+        return HttpResponse::not_found();
+    };
+    match control {
+        Some(_) if method != "POST" => HttpResponse::text(405, "use POST"),
+        Some(control) => {
+            watcher.control(control);
+            json(&watcher.status())
+        }
+        None => json(&watcher.status()),
+    }
+}
+
+/// serve the admin API, forever
+pub async fn serve_admin(admin_conf: &AdminConf, runner: Arc<Runner>) -> Result<(), RescError> {
+    http::serve(&admin_conf.address, move |request: HttpRequest| {
+        handle(Arc::clone(&runner), request)
+    }).await?;
+    Ok(())
+}
@@ -0,0 +1,152 @@
+use {
+    crate::*,
+    log::*,
+    serde::Deserialize,
+    std::sync::Arc,
+    tokio::{
+        fs::{File, OpenOptions},
+        io::AsyncWriteExt,
+        sync::Mutex,
+    },
+};
+
+/// Configuration of the audit log, where every decision
+/// of resc is durably appended
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+pub struct AuditConf {
+    /// the Redis stream where to append the records
+    pub stream: Option<String>,
+    /// approximate max length of the stream, the oldest
+    /// records being trimmed
+    #[serde(default = "AuditConf::default_max_len")]
+    pub max_len: usize,
+    /// the path of a file where to append the records,
+    /// one JSON object per line
+    pub file: Option<String>,
+}
+
+impl AuditConf {
+    pub fn default_max_len() -> usize {
+        100_000
+    }
+}
+
+/// The audit log, appending the messages also published on the
+/// listener channel, always in their JSON form.
+///
+/// It's cheap to clone, the clones sharing the same file.
+#[derive(Clone)]
+pub struct AuditLog {
+    conf: AuditConf,
+    /// the file, opened on first write
+    file: Arc<Mutex<Option<File>>>,
+}
+
+impl AuditLog {
+    pub fn new(conf: &AuditConf) -> Self {
+        Self {
+            conf: conf.clone(),
+            file: Arc::new(Mutex::new(None)),
+        }
+    }
+
+    /// append the message to the stream and to the file.
+    ///
+    /// A failure is logged but doesn't stop the handling of events.
+    pub async fn append(
+        &self,
+        backend: &dyn QueueBackend,
+        message: &ListenerMessage,
+    ) {
+        let Some(record) = message.render(ListenerFormat::Json) else {
+            return;
+        };
+        if let Some(stream) = &self.conf.stream {
+            if let Err(e) = self.add_to_stream(backend, stream, message, &record).await {
+                warn!("can't append to audit stream {:?}: {}", stream, e);
+            }
+        }
+        if let Some(path) = &self.conf.file {
+            if let Err(e) = self.write_line(path, &record).await {
+                warn!("can't write to audit file {:?}: {}", path, e);
+            }
+        }
+    }
+
+    async fn add_to_stream(
+        &self,
+        backend: &dyn QueueBackend,
+        stream: &str,
+        message: &ListenerMessage,
+        record: &str,
+    ) -> Result<(), RescError> {
+        let Some(mut con) = backend.redis() else {
+            return Err(RescError::RedisRequired("audit stream"));
+        };
+        let _: () = redis::cmd("XADD")
+            .arg(stream)
+            .arg("MAXLEN")
+            .arg("~")
+            .arg(self.conf.max_len)
+            .arg("*")
+            .arg("kind")
+            .arg(serde_json::to_string(&message.kind).unwrap().trim_matches('"'))
+            .arg("record")
+            .arg(record)
+            .query_async(&mut con)
+            .await?;
+        Ok(())
+    }
+
+    async fn write_line(&self, path: &str, record: &str) -> std::io::Result<()> {
+        let mut file = self.file.lock().await;
+        if file.is_none() {
+            *file = Some(OpenOptions::new().create(true).append(true).open(path).await?);
+        }
+        let file = file.as_mut().unwrap();
+        // one write per line, so that lines of several
+        // instances aren't interleaved
+        file.write_all(format!("{}\n", record).as_bytes()).await?;
+        file.flush().await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use {
+        super::*,
+        serde_json::Value,
+    };
+
+    #[tokio::test]
+    async fn records_are_appended_as_json_lines() {
+        let path = std::env::temp_dir().join(format!("resc-audit-{}.jsonl", std::process::id()));
+        std::fs::write(&path, "{\"kind\":\"EARLIER\"}\n").unwrap();
+        let audit = AuditLog::new(&AuditConf {
+            // not available with this backend, which doesn't prevent writing the file
+            stream: Some("resc/audit".to_owned()),
+            max_len: AuditConf::default_max_len(),
+            file: Some(path.to_str().unwrap().to_owned()),
+        });
+        let backend = MemoryBackend::new();
+        audit.append(&backend, &ListenerMessage {
+            event: Some("acq/a".to_owned()),
+            ..ListenerMessage::new(ListenerEvent::Taken, "events/taken")
+        }).await;
+        audit.clone().append(&backend, &ListenerMessage {
+            event: Some("acq/a".to_owned()),
+            ..ListenerMessage::new(ListenerEvent::Done, "events/taken")
+        }).await;
+        let content = std::fs::read_to_string(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        let records: Vec<Value> = content.lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect();
+        let kinds: Vec<&str> = records.iter()
+            .map(|record| record["kind"].as_str().unwrap())
+            .collect();
+        assert_eq!(kinds, vec!["EARLIER", "TAKEN", "DONE"]);
+        assert_eq!(records[2]["event"], "acq/a");
+        assert_eq!(records[2]["version"], LISTENER_MESSAGE_VERSION);
+    }
+}
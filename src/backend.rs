@@ -0,0 +1,293 @@
+use {
+    crate::*,
+    async_trait::async_trait,
+    log::*,
+    std::{
+        future::Future,
+        time::Duration,
+    },
+};
+
+/// first delay between two attempts to lease a task, when
+/// the queue is empty. It's doubled up to `MAX_LEASE_POLL_DELAY`
+const MIN_LEASE_POLL_DELAY: Duration = Duration::from_millis(10);
+
+/// max delay between two attempts to lease a task
+const MAX_LEASE_POLL_DELAY: Duration = Duration::from_millis(500);
+
+/// Takes the events of input queues. It's dedicated to one
+/// watcher, as taking may block while waiting for an event.
+#[async_trait]
+pub trait EventTaker: Send {
+    /// atomically move the oldest event of the input queue to the
+    /// taken queue and return it, waiting at most `timeout` seconds
+    /// for an event to come
+    async fn take(
+        &mut self,
+        input_queue: &str,
+        taken_queue: &str,
+        timeout: f64,
+    ) -> Result<Option<String>, RescError>;
+}
+
+/// The operations on queues, sets and channels the watchers
+/// and the dispatchers need.
+///
+/// Queues are lists where new elements are pushed at the head
+/// and taken at the tail, sets are sorted sets of tasks scored
+/// by the time they were pushed.
+#[async_trait]
+pub trait QueueBackend: Send + Sync {
+
+    /// make a taker, for a watcher
+    async fn taker(&self) -> Result<Box<dyn EventTaker>, RescError>;
+
+    /// push a value at the head of a queue
+    async fn push(&self, queue: &str, value: &str) -> Result<(), RescError>;
+
+    /// atomically add a value to a set and push it at the head of a
+    /// queue, unless it's already in the set, returning whether
+    /// it was pushed
+    async fn push_deduplicated(
+        &self,
+        queue: &str,
+        set: &str,
+        value: &str,
+        score: f64,
+    ) -> Result<bool, RescError>;
+
+    /// atomically move the tail of a queue to the head of another
+    /// one, returning the moved value, if any
+    async fn move_tail(&self, from: &str, to: &str) -> Result<Option<String>, RescError>;
+
+    /// remove the first occurence of a value from a queue
+    async fn remove(&self, queue: &str, value: &str) -> Result<(), RescError>;
+
+    /// get the score of a member of a set, if it's present
+    async fn score(&self, set: &str, member: &str) -> Result<Option<f64>, RescError>;
+
+    /// add a member to a set, or update its score
+    async fn add_to_set(&self, set: &str, member: &str, score: f64) -> Result<(), RescError>;
+
+    /// remove a member from a set, returning whether it was there
+    async fn remove_from_set(&self, set: &str, member: &str) -> Result<bool, RescError>;
+
+    /// increment a counter, which expires after `ttl`,
+    /// returning its new value
+    async fn increment(&self, key: &str, ttl: Duration) -> Result<u64, RescError>;
+
+    /// delete a key
+    async fn delete(&self, key: &str) -> Result<(), RescError>;
+
+    /// set a key which expires after `ttl`
+    async fn set_expiring(&self, key: &str, value: &str, ttl: Duration) -> Result<(), RescError>;
+
+    /// if the heartbeat key doesn't exist, atomically move the
+    /// tasks of the taken queue back to the head of the task
+    /// queue, adding them to the task set with the given score.
+    /// Tasks already in the set are only removed from the taken
+    /// queue. Returns the requeued tasks.
+    async fn requeue_orphans(
+        &self,
+        taken_queue: &str,
+        queue: &str,
+        set: Option<&str>,
+        heartbeat: &str,
+        score: f64,
+    ) -> Result<Vec<String>, RescError>;
+
+    /// atomically move the tail of a queue to a lease set, scored
+    /// by the lease deadline, returning the leased value, if any
+    async fn lease(&self, queue: &str, leases: &str, deadline: f64) -> Result<Option<String>, RescError>;
+
+    /// change the deadline of a lease, returning whether the
+    /// lease still exists
+    async fn extend_lease(&self, leases: &str, task: &str, deadline: f64) -> Result<bool, RescError>;
+
+    /// atomically move the tasks whose lease deadline is past back
+    /// to the head of their queue, adding them to the task set with
+    /// the `now` score. Tasks already in the set are only removed
+    /// from the lease set. Returns the requeued tasks.
+    async fn requeue_expired_leases(
+        &self,
+        leases: &str,
+        queue: &str,
+        set: Option<&str>,
+        now: f64,
+    ) -> Result<Vec<String>, RescError>;
+
+    /// remove the members of a set scored below `min_score`,
+    /// and return the other ones
+    async fn live_members(&self, set: &str, min_score: f64) -> Result<Vec<String>, RescError>;
+
+    /// take a lock for `ttl`, or renew it if it's already owned
+    /// by `owner`, returning whether `owner` owns it
+    async fn lock(&self, key: &str, owner: &str, ttl: Duration) -> Result<bool, RescError>;
+
+    /// release a lock, if it's owned by `owner`
+    async fn unlock(&self, key: &str, owner: &str) -> Result<(), RescError>;
+
+    /// record the arrival of a member in a join, which is forgotten
+    /// `ttl` after the last arrival, and return whether all the
+    /// expected members arrived. Only one member completes a join,
+    /// but it completes it again on replay, until the join is
+    /// completed with `complete_join` or released with `release_join`
+    async fn arrive_in_join(
+        &self,
+        key: &str,
+        member: &str,
+        expected: &[String],
+        ttl: Duration,
+    ) -> Result<bool, RescError>;
+
+    /// let another arrival complete the join, if `member` completed it
+    async fn release_join(&self, key: &str, member: &str) -> Result<(), RescError>;
+
+    /// forget the join, if `member` completed it
+    async fn complete_join(&self, key: &str, member: &str) -> Result<(), RescError>;
+
+    /// record an event as the last one of its key in the `pending`
+    /// hash, and add the key to the `due` set, scored by `due_time`,
+    /// unless it's already there
+    async fn debounce(
+        &self,
+        due: &str,
+        pending: &str,
+        key: &str,
+        event: &str,
+        due_time: f64,
+    ) -> Result<(), RescError>;
+
+    /// atomically move at most `max` events whose key is due at `now`
+    /// from the `pending` hash to the `firing` hash, returning the
+    /// moved keys and events
+    async fn close_windows(
+        &self,
+        due: &str,
+        pending: &str,
+        firing: &str,
+        now: f64,
+        max: usize,
+    ) -> Result<Vec<(String, String)>, RescError>;
+
+    /// the keys and values of a hash
+    async fn hash_entries(&self, hash: &str) -> Result<Vec<(String, String)>, RescError>;
+
+    /// remove a key from a hash
+    async fn remove_from_hash(&self, hash: &str, key: &str) -> Result<(), RescError>;
+
+    /// lease a task from a queue, waiting at most `timeout`
+    /// seconds for a task to come
+    ///
+    /// As a lease can't be taken with a blocking command, the
+    /// queue is polled, with a delay growing from 10ms to 500ms
+    /// while it's empty.
+    ///
+    /// The member of the lease set is the task itself, so
+    /// identical tasks leased together share their lease.
+    async fn take_leased(
+        &self,
+        queue: &str,
+        leases: &str,
+        lease: Duration,
+        timeout: f64,
+    ) -> Result<Option<String>, RescError> {
+        let deadline = tokio::time::Instant::now() + Duration::from_secs_f64(timeout);
+        let mut delay = MIN_LEASE_POLL_DELAY;
+        loop {
+            let lease_deadline = precise_now_secs() + lease.as_secs_f64();
+            if let Some(task) = self.lease(queue, leases, lease_deadline).await? {
+                return Ok(Some(task));
+            }
+            if tokio::time::Instant::now() + delay > deadline {
+                return Ok(None);
+            }
+            tokio::time::sleep(delay).await;
+            delay = (delay * 2).min(MAX_LEASE_POLL_DELAY);
+        }
+    }
+
+    /// publish a message on a channel
+    async fn publish(&self, channel: &str, message: &str) -> Result<(), RescError>;
+
+    /// the Redis connection, for the features relying on Redis
+    /// specific structures and scripts (lineage, audit
+    /// stream), not available with other backends
+    fn redis(&self) -> Option<RedisConnection> {
+        None
+    }
+}
+
+/// run a future, extending the lease of its task until it's done,
+/// so that it's not requeued while it's still being handled
+pub async fn with_lease<F: Future>(
+    backend: &dyn QueueBackend,
+    leases: &str,
+    task: &str,
+    lease: Duration,
+    future: F,
+) -> F::Output {
+    let extending = async {
+        loop {
+            tokio::time::sleep(lease / 3).await;
+            let deadline = precise_now_secs() + lease.as_secs_f64();
+            match backend.extend_lease(leases, task, deadline).await {
+                Ok(true) => {}
+                Ok(false) => warn!("lease of {:?} in {:?} lost", task, leases),
+                Err(e) => warn!("lease of {:?} in {:?} not extended: {}", task, leases, e),
+            }
+        }
+    };
+    tokio::select! {
+        output = future => output,
+        _ = extending => unreachable!(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const LEASE: Duration = Duration::from_millis(300);
+
+    #[tokio::test]
+    async fn expired_lease_is_requeued() {
+        let memory = MemoryBackend::new();
+        memory.push("trt/todo", "trt/a").await.unwrap();
+        let task = memory.take_leased("trt/todo", "trt/leases", LEASE, 1.0).await.unwrap();
+        assert_eq!(task.as_deref(), Some("trt/a"));
+        assert!(memory.queue("trt/todo").is_empty());
+        let requeued = memory.requeue_expired_leases("trt/leases", "trt/todo", None, precise_now_secs())
+            .await.unwrap();
+        assert!(requeued.is_empty());
+        tokio::time::sleep(LEASE + Duration::from_millis(50)).await;
+        let requeued = memory.requeue_expired_leases("trt/leases", "trt/todo", None, precise_now_secs())
+            .await.unwrap();
+        assert_eq!(requeued, vec!["trt/a"]);
+        assert_eq!(memory.queue("trt/todo"), vec!["trt/a"]);
+        assert!(memory.set("trt/leases").is_empty());
+    }
+
+    #[tokio::test]
+    async fn lease_is_extended_while_handled() {
+        let memory = MemoryBackend::new();
+        memory.push("trt/todo", "trt/a").await.unwrap();
+        memory.take_leased("trt/todo", "trt/leases", LEASE, 1.0).await.unwrap();
+        let first_deadline = memory.set("trt/leases")["trt/a"];
+        let handling = async {
+            // lasting longer than the lease
+            tokio::time::sleep(LEASE * 2).await;
+            let requeued = memory.requeue_expired_leases("trt/leases", "trt/todo", None, precise_now_secs())
+                .await.unwrap();
+            assert!(requeued.is_empty());
+            "done"
+        };
+        let handled = with_lease(&memory, "trt/leases", "trt/a", LEASE, handling).await;
+        assert_eq!(handled, "done");
+        assert!(memory.set("trt/leases")["trt/a"] > first_deadline + LEASE.as_secs_f64());
+        assert!(memory.queue("trt/todo").is_empty());
+        // a lost lease can't be extended
+        memory.remove_from_set("trt/leases", "trt/a").await.unwrap();
+        assert!(!memory.extend_lease("trt/leases", "trt/a", precise_now_secs()).await.unwrap());
+    }
+}
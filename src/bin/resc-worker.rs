@@ -0,0 +1,49 @@
+//! The resc-worker command line: takes the tasks of a queue, following
+//! the worker protocol of resc, and runs a shell command for each one
+//!
+//! Usage: `resc-worker myworker.hjson`
+
+use {
+    log::*,
+    resc::{internal::SerdeFormat, *},
+    std::{env, path::Path, process::ExitCode, sync::Arc},
+};
+
+#[tokio::main]
+async fn main() -> ExitCode {
+    configure_logger();
+    let args: Vec<String> = env::args().collect();
+    let [_, config_filename] = args.as_slice() else {
+        eprintln!("usage: resc-worker <configuration file>");
+        return ExitCode::FAILURE;
+    };
+    let read: Result<CommandWorkerConf, ConfError> = SerdeFormat::read_file(Path::new(config_filename))
+        .and_then(|conf: CommandWorkerConf| {
+            conf.redis.validate()?;
+            Ok(conf)
+        });
+    let conf = match read {
+        Ok(conf) => conf,
+        Err(e) => {
+            eprintln!("{}", e);
+            return ExitCode::FAILURE;
+        }
+    };
+    let worked = async {
+        let backend = RedisBackend::connect(&conf.redis).await?;
+        let worker = Arc::new(Worker::new(conf.worker.clone(), Arc::new(backend)));
+        let conf = Arc::new(conf);
+        worker.run(move |task| {
+            let conf = Arc::clone(&conf);
+            async move { conf.run(task).await }
+        }).await
+    };
+    match worked.await {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(e) => {
+            error!("worker stopped: {}", e);
+            eprintln!("{}", e);
+            ExitCode::FAILURE
+        }
+    }
+}
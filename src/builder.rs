@@ -0,0 +1,333 @@
+use {
+    crate::*,
+    regex::Regex,
+    std::time::Duration,
+};
+
+/// Builds a [Conf], with the same defaults as
+/// when it's read from a file
+pub struct ConfBuilder {
+    conf: Conf,
+}
+
+impl Conf {
+    pub fn builder(redis_url: &str, listener_channel: &str) -> ConfBuilder {
+        ConfBuilder {
+            conf: Conf {
+                redis: RedisConf::new(redis_url),
+                listener_channel: listener_channel.to_owned(),
+                listener_format: ListenerFormat::default(),
+                concurrency: Conf::default_concurrency(),
+                scheduled_set: Conf::default_scheduled_set(),
+                watchers: Vec::new(),
+                schedules: Vec::new(),
+                metrics: None,
+                admin: None,
+                workflows: Vec::new(),
+                lineage: None,
+                audit: None,
+                reapers: Vec::new(),
+                locks: None,
+                watch_conf_file: false,
+            },
+        }
+    }
+}
+
+impl ConfBuilder {
+    /// replace the single server of the builder with
+    /// sentinels or a cluster
+    pub fn redis(mut self, redis: RedisConf) -> Self {
+        self.conf.redis = redis;
+        self
+    }
+    pub fn listener_format(mut self, format: ListenerFormat) -> Self {
+        self.conf.listener_format = format;
+        self
+    }
+    pub fn concurrency(mut self, concurrency: usize) -> Self {
+        self.conf.concurrency = concurrency;
+        self
+    }
+    pub fn scheduled_set(mut self, scheduled_set: &str) -> Self {
+        self.conf.scheduled_set = scheduled_set.to_owned();
+        self
+    }
+    pub fn watcher(mut self, watcher: WatcherConf) -> Self {
+        self.conf.watchers.push(watcher);
+        self
+    }
+    pub fn schedule(mut self, schedule: ScheduleConf) -> Self {
+        self.conf.schedules.push(schedule);
+        self
+    }
+    pub fn workflow(mut self, workflow: WorkflowConf) -> Self {
+        self.conf.workflows.push(workflow);
+        self
+    }
+    pub fn metrics(mut self, metrics: MetricsConf) -> Self {
+        self.conf.metrics = Some(metrics);
+        self
+    }
+    pub fn admin(mut self, admin: AdminConf) -> Self {
+        self.conf.admin = Some(admin);
+        self
+    }
+    pub fn lineage(mut self, lineage: LineageConf) -> Self {
+        self.conf.lineage = Some(lineage);
+        self
+    }
+    pub fn audit(mut self, audit: AuditConf) -> Self {
+        self.conf.audit = Some(audit);
+        self
+    }
+    pub fn reaper(mut self, reaper: ReaperConf) -> Self {
+        self.conf.reapers.push(reaper);
+        self
+    }
+    pub fn locks(mut self, locks: LockConf) -> Self {
+        self.conf.locks = Some(locks);
+        self
+    }
+    /// validate the configuration and compile its workflows,
+    /// as is done for a configuration file
+    pub fn build(mut self) -> Result<Conf, ConfError> {
+        self.conf.compile_workflows()?;
+        self.conf.expand_partitions();
+        self.conf.validate()?;
+        Ok(self.conf)
+    }
+}
+
+impl WatcherConf {
+    pub fn new(input_queue: &str) -> Self {
+        Self {
+            input_queue: input_queue.to_owned(),
+            taken_queue: None,
+            concurrency: None,
+            lease: None,
+            partitions: None,
+            rules: Vec::new(),
+        }
+    }
+    pub fn taken_queue(mut self, taken_queue: &str) -> Self {
+        self.taken_queue = Some(taken_queue.to_owned());
+        self
+    }
+    pub fn concurrency(mut self, concurrency: usize) -> Self {
+        self.concurrency = Some(concurrency);
+        self
+    }
+    pub fn lease(mut self, lease: Duration) -> Self {
+        self.lease = Some(lease);
+        self
+    }
+    pub fn partitions(mut self, partitions: usize) -> Self {
+        self.partitions = Some(partitions);
+        self
+    }
+    pub fn rule(mut self, rule: Rule) -> Self {
+        self.rules.push(rule);
+        self
+    }
+}
+
+/// Builds a [Rule]
+pub struct RuleBuilder {
+    name: String,
+    on: String,
+    fetchers: Vec<Fetcher>,
+    makers: Vec<Maker>,
+    debounce: Option<Debounce>,
+    join: Option<Join>,
+}
+
+impl Rule {
+    /// start building a rule applying to the events
+    /// matching the `on` regex
+    pub fn builder(name: &str, on: &str) -> RuleBuilder {
+        RuleBuilder {
+            name: name.to_owned(),
+            on: on.to_owned(),
+            fetchers: Vec::new(),
+            makers: Vec::new(),
+            debounce: None,
+            join: None,
+        }
+    }
+}
+
+impl RuleBuilder {
+    pub fn fetch(mut self, url: &str, returns: &str) -> Self {
+        self.fetchers.push(Fetcher {
+            url: Pattern::from(url),
+            returns: returns.to_owned(),
+            timeout: Fetcher::default_timeout(),
+        });
+        self
+    }
+    pub fn make(mut self, maker: Maker) -> Self {
+        self.makers.push(maker);
+        self
+    }
+    pub fn debounce(mut self, window: Duration, key: &str) -> Self {
+        self.debounce = Some(Debounce {
+            window,
+            key: Pattern::from(key),
+        });
+        self
+    }
+    pub fn join(mut self, join: Join) -> Self {
+        self.join = Some(join);
+        self
+    }
+    /// compile the `on` regex and build the rule, which
+    /// must make at least one task
+    pub fn build(self) -> Result<Rule, ConfError> {
+        if self.makers.is_empty() {
+            return Err(ConfError::NoMaker(self.name));
+        }
+        let on_regex = Regex::new(&self.on)
+            .map_err(|e| ConfError::InvalidRegex(self.on.clone(), e.to_string()))?;
+        let makers = if self.makers.len() == 1 {
+            Makers::Single(self.makers.into_iter().next().unwrap())
+        } else {
+            Makers::Multiple(self.makers)
+        };
+        Ok(Rule {
+            name: self.name,
+            on_regex,
+            fetchers: self.fetchers,
+            makers,
+            debounce: self.debounce,
+            join: self.join,
+        })
+    }
+}
+
+impl Maker {
+    /// a maker pushing the `task` pattern to the `queue` pattern
+    pub fn new(task: &str, queue: &str) -> Self {
+        Self {
+            name: None,
+            task: Pattern::from(task),
+            queue: Pattern::from(queue),
+            set: None,
+            delay: None,
+            at: None,
+            retry: None,
+        }
+    }
+    pub fn name(mut self, name: &str) -> Self {
+        self.name = Some(name.to_owned());
+        self
+    }
+    pub fn set(mut self, set: &str) -> Self {
+        self.set = Some(Pattern::from(set));
+        self
+    }
+    pub fn delay(mut self, delay: Duration) -> Self {
+        self.delay = Some(delay);
+        self
+    }
+    pub fn at(mut self, at: &str) -> Self {
+        self.at = Some(Pattern::from(at));
+        self
+    }
+    pub fn retry(mut self, retry: Retry) -> Self {
+        self.retry = Some(retry);
+        self
+    }
+}
+
+impl Join {
+    /// a join, on the `key` pattern, waiting for the `expect`
+    /// patterns, with the default member and ttl
+    pub fn new(key: &str, expect: &[&str]) -> Self {
+        Self {
+            key: Pattern::from(key),
+            member: Pattern::default_task(),
+            expect: expect.iter().map(|&e| Pattern::from(e)).collect(),
+            ttl: Join::default_ttl(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ruleset() -> Vec<Rule> {
+        vec![
+            Rule::builder("trt", r"^acq/(?P<process>\w+)/(?P<product>\w+)$")
+                .make(
+                    Maker::new("trt/${process}/${product}", "trt/${process}/todo")
+                        .set("trt/${process}/set")
+                )
+                .make(Maker::new("archive/${input_task}", "archive"))
+                .build()
+                .unwrap(),
+            Rule::builder("publish", r"^trt/(?P<process>\w+)/done$")
+                .make(Maker::new("publish/${process}", "publish").delay(Duration::from_secs(60)))
+                .build()
+                .unwrap(),
+        ]
+    }
+
+    #[tokio::test]
+    async fn rule_results() {
+        let rules = ruleset();
+        let results = rules[0].results("acq/a/1").await.unwrap();
+        assert_eq!(results.len(), 2);
+        assert_eq!(results[0].task, "trt/a/1");
+        assert_eq!(results[0].queue, "trt/a/todo");
+        assert_eq!(results[0].set.as_deref(), Some("trt/a/set"));
+        assert_eq!(results[0].due, None);
+        assert_eq!(results[1].task, "archive/acq/a/1");
+        assert_eq!(results[1].queue, "archive");
+        let results = rules[1].results("trt/a/done").await.unwrap();
+        assert_eq!(results[0].task, "publish/a");
+        assert!(results[0].due.unwrap() >= now_secs() + 59.0);
+    }
+
+    #[tokio::test]
+    async fn rules_only_apply_to_matching_events() {
+        let rules = ruleset();
+        let matching: Vec<&str> = rules.iter()
+            .filter(|rule| rule.is_match("trt/a/done"))
+            .map(|rule| rule.name.as_str())
+            .collect();
+        assert_eq!(matching, vec!["publish"]);
+        assert!(matches!(
+            rules[0].results("trt/a/done").await,
+            Err(RescError::NotMatching(..)),
+        ));
+    }
+
+    #[test]
+    fn rule_must_make_a_task() {
+        assert!(matches!(
+            Rule::builder("nothing", "^acq/").build(),
+            Err(ConfError::NoMaker(_)),
+        ));
+        assert!(matches!(
+            Rule::builder("bad regex", "^acq/(").make(Maker::new("t", "q")).build(),
+            Err(ConfError::InvalidRegex(..)),
+        ));
+    }
+
+    #[test]
+    fn conf_with_invalid_partitioned_watcher_is_refused() {
+        let rule = Rule::builder("trt", "^acq/").make(Maker::new("t", "q")).build().unwrap();
+        let built = Conf::builder("redis://127.0.0.1/", "listener")
+            .watcher(WatcherConf::new("events").partitions(0).rule(rule.clone()))
+            .build();
+        assert!(matches!(built, Err(ConfError::NoPartition(_))));
+        let conf = Conf::builder("redis://127.0.0.1/", "listener")
+            .watcher(WatcherConf::new("events").partitions(2).rule(rule))
+            .build()
+            .unwrap();
+        let queues: Vec<&str> = conf.watchers.iter().map(|w| w.input_queue.as_str()).collect();
+        assert_eq!(queues, vec!["events/0", "events/1"]);
+    }
+}
@@ -0,0 +1,125 @@
+use {
+    crate::*,
+    anyhow::bail,
+    log::*,
+    regex::Regex,
+    serde::Deserialize,
+    std::{
+        process::Stdio,
+        time::Duration,
+    },
+    tokio::process::Command,
+};
+
+/// The configuration of the resc-worker binary, which runs
+/// a shell command for every task
+#[derive(Debug, Deserialize)]
+pub struct CommandWorkerConf {
+    pub redis: RedisConf,
+    /// the queues of the worker
+    pub worker: WorkerConf,
+    /// the command, run with `sh -c`
+    pub command: String,
+    /// an optional regex, whose named groups are passed to the
+    /// command as environment variables. A task not matching it
+    /// fails without the command being run
+    #[serde(default, with = "serde_regex", alias = "on")]
+    pub on_regex: Option<Regex>,
+    /// the max duration of the command, after which it's killed
+    /// and the task fails
+    #[serde(default, deserialize_with = "deserialize_option_duration")]
+    pub timeout: Option<Duration>,
+}
+
+impl CommandWorkerConf {
+    /// the environment variables given to the command: the
+    /// task, as RESC_TASK, and the named groups of the regex
+    fn env(&self, task: &str) -> anyhow::Result<Vec<(String, String)>> {
+        let mut env = vec![("RESC_TASK".to_owned(), task.to_owned())];
+        if let Some(regex) = &self.on_regex {
+            let Some(caps) = regex.captures(task) else {
+                bail!("task {:?} doesn't match {:?}", task, regex.as_str());
+            };
+            for name in regex.capture_names().flatten() {
+                if let Some(value) = caps.name(name) {
+                    env.push((name.to_owned(), value.as_str().to_owned()));
+                }
+            }
+        }
+        Ok(env)
+    }
+
+    /// run the command for a task, failing if it doesn't
+    /// exit with success in time
+    pub async fn run(&self, task: String) -> anyhow::Result<()> {
+        let env = self.env(&task)?;
+        info!("running {:?} for task {:?}", &self.command, &task);
+        let mut child = Command::new("sh")
+            .arg("-c")
+            .arg(&self.command)
+            .envs(env)
+            .stdin(Stdio::null())
+            .kill_on_drop(true)
+            .spawn()?;
+        let status = match self.timeout {
+            Some(timeout) => match tokio::time::timeout(timeout, child.wait()).await {
+                Ok(status) => status?,
+                Err(_) => {
+                    child.kill().await?;
+                    bail!("command timed out after {:?}", timeout);
+                }
+            },
+            None => child.wait().await?,
+        };
+        if !status.success() {
+            bail!("command failed: {}", status);
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use {
+        super::*,
+        std::time::Instant,
+    };
+
+    fn conf(command: &str, timeout: Option<&str>) -> CommandWorkerConf {
+        let timeout = timeout.map(|t| format!("timeout: \"{}\"", t)).unwrap_or_default();
+        deser_hjson::from_str(&format!(r#"{{
+            redis: {{ url: "redis://127.0.0.1/" }}
+            worker: {{
+                queue: "trt/todo"
+                done_queue: "events"
+            }}
+            command: "{}"
+            on: "^trt/(?P<product>\\w+)$"
+            {}
+        }}"#, command, timeout)).unwrap()
+    }
+
+    #[tokio::test]
+    async fn command_gets_the_task_and_its_groups() {
+        let conf = conf(r#"test \"$RESC_TASK\" = trt/a && test \"$product\" = a"#, None);
+        conf.run("trt/a".to_owned()).await.unwrap();
+        assert!(conf.run("trt/b".to_owned()).await.is_err());
+        // a task not matching the regex fails without running the command
+        let e = conf.run("other".to_owned()).await.unwrap_err();
+        assert!(e.to_string().contains("doesn't match"));
+    }
+
+    #[tokio::test]
+    async fn failure_status_fails_the_task() {
+        let e = conf("exit 3", None).run("trt/a".to_owned()).await.unwrap_err();
+        assert!(e.to_string().contains("exit status: 3"), "unexpected error: {}", e);
+    }
+
+    #[tokio::test]
+    async fn command_is_killed_after_timeout() {
+        let start = Instant::now();
+        let e = conf("sleep 10", Some("200ms")).run("trt/a".to_owned()).await.unwrap_err();
+        assert!(e.to_string().contains("timed out"), "unexpected error: {}", e);
+        assert!(start.elapsed() < Duration::from_secs(5));
+    }
+}
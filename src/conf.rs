@@ -1,5 +1,6 @@
 use {
     crate::*,
+    log::*,
     serde::Deserialize,
     std::{
         path::PathBuf,
@@ -7,23 +8,181 @@ use {
 };
 
 
-/// Redis access configuration
-#[derive(Debug, Deserialize)]
-pub struct RedisConf {
-    pub url: String,
-}
-
 /// The configuration of Resc, as read from a JSON file
 #[derive(Debug, Deserialize)]
 pub struct Conf {
     pub redis: RedisConf,
     pub listener_channel: String,
+    /// the format of the messages published on the listener channel
+    #[serde(default)]
+    pub listener_format: ListenerFormat,
+    /// max number of events a watcher handles at the same time.
+    /// With the default value of 1, events are handled one after
+    /// the other, in the order of the input queue
+    #[serde(default = "Conf::default_concurrency")]
+    pub concurrency: usize,
+    /// the sorted set where delayed tasks wait, scored
+    /// by their due time
+    #[serde(default = "Conf::default_scheduled_set")]
+    pub scheduled_set: String,
+    #[serde(default)]
     pub watchers: Vec<WatcherConf>,
+    /// the time triggered event sources
+    #[serde(default)]
+    pub schedules: Vec<ScheduleConf>,
+    /// when present, the metrics are exposed over HTTP
+    pub metrics: Option<MetricsConf>,
+    /// when present, the admin API is served over HTTP
+    pub admin: Option<AdminConf>,
+    /// the workflows, compiled into watcher rules
+    #[serde(default)]
+    pub workflows: Vec<WorkflowConf>,
+    /// when present, the lineage of the tasks is recorded
+    pub lineage: Option<LineageConf>,
+    /// when present, every decision is appended to an audit log
+    pub audit: Option<AuditConf>,
+    /// the taken queues of workers whose tasks are requeued
+    /// when the workers stop beating
+    #[serde(default)]
+    pub reapers: Vec<ReaperConf>,
+    /// when present, each watcher not in lease mode is run by only
+    /// one of the resc instances sharing this configuration
+    pub locks: Option<LockConf>,
+    /// whether the configuration is reloaded when its
+    /// file is modified
+    #[serde(default)]
+    pub watch_conf_file: bool,
+}
+
+impl Conf {
+    pub fn default_concurrency() -> usize {
+        1
+    }
+    pub fn default_scheduled_set() -> String {
+        "resc/scheduled".to_owned()
+    }
+    /// check the consistency of the configuration, beyond
+    /// what's checked on deserialization
+    pub fn validate(&self) -> Result<(), ConfError> {
+        for watcher in &self.watchers {
+            watcher.validate()?;
+        }
+        for (i, schedule) in self.schedules.iter().enumerate() {
+            schedule.validate()?;
+            if self.schedules[..i].iter().any(|s| s.name == schedule.name) {
+                return Err(ConfError::DuplicateSchedule(schedule.name.clone()));
+            }
+        }
+        for workflow in &self.workflows {
+            workflow.validate()?;
+        }
+        self.redis.validate()?;
+        if self.redis.is_cluster() {
+            check_same_slot(&[&self.scheduled_set, &invalid_scheduled_key(&self.scheduled_set)])?;
+            for watcher in &self.watchers {
+                watcher.check_cluster_slots(&self.scheduled_set)?;
+            }
+            for reaper in &self.reapers {
+                reaper.check_cluster_slots()?;
+            }
+        }
+        Ok(())
+    }
+    /// replace the watchers of partitioned input queues
+    /// with one watcher per partition
+    pub fn expand_partitions(&mut self) {
+        self.watchers = std::mem::take(&mut self.watchers)
+            .into_iter()
+            .flat_map(WatcherConf::partitioned)
+            .collect();
+    }
+    /// add the rules of the workflows to the watchers of their input
+    /// queues, creating those watchers when needed.
+    ///
+    /// The workflows are validated first, as only a valid
+    /// workflow can be compiled.
+    pub fn compile_workflows(&mut self) -> Result<(), ConfError> {
+        for workflow in &self.workflows {
+            workflow.validate()?;
+            let mut rules = workflow.compile();
+            match self.watchers.iter_mut().find(|w| w.input_queue == workflow.input_queue) {
+                Some(watcher) => {
+                    watcher.rules.append(&mut rules);
+                }
+                None => {
+                    self.watchers.push(WatcherConf {
+                        input_queue: workflow.input_queue.clone(),
+                        taken_queue: None,
+                        concurrency: None,
+                        lease: None,
+                        partitions: None,
+                        rules,
+                    });
+                }
+            }
+        }
+        Ok(())
+    }
 }
 
 pub fn read_file(filename: &str) -> Result<Conf, ConfError> {
     let start = std::time::Instant::now();
-    let conf = SerdeFormat::read_file(&PathBuf::from(&filename));
+    let mut conf: Conf = SerdeFormat::read_file(&PathBuf::from(&filename))?;
+    // the watchers made from workflows and partitions are validated too
+    conf.compile_workflows()?;
+    conf.expand_partitions();
+    conf.validate()?;
     debug!("Conf read in {:?}", start.elapsed());
-    conf
+    Ok(conf)
+}
+
+#[cfg(test)]
+mod tests {
+    use {
+        super::*,
+        std::time::Duration,
+    };
+
+    #[test]
+    fn rule_without_maker_is_refused() {
+        let path = std::env::temp_dir().join(format!("resc-no-maker-{}.hjson", std::process::id()));
+        std::fs::write(&path, r#"{
+            redis: { url: "redis://127.0.0.1/" }
+            listener_channel: events
+            watchers: [{
+                input_queue: global/events
+                rules: [{
+                    name: lazy
+                    on: "^acq/(?P<product>\\w+)$"
+                    make: []
+                }]
+            }]
+        }"#).unwrap();
+        let read = read_file(path.to_str().unwrap());
+        std::fs::remove_file(&path).unwrap();
+        assert!(matches!(read, Err(ConfError::NoMaker(name)) if name == "lazy"));
+    }
+
+    #[test]
+    fn delayed_tasks_must_be_in_the_slot_of_the_scheduled_set() {
+        let cluster: RedisConf = deser_hjson::from_str(r#"{
+            cluster: { nodes: [ "redis://10.0.0.1/" ] }
+        }"#).unwrap();
+        let conf = |queue: &str| {
+            let rule = Rule::builder("later", r"^acq/(?P<product>\w+)$")
+                .make(Maker::new("trt/${product}", queue).delay(Duration::from_secs(60)))
+                .build()
+                .unwrap();
+            Conf::builder("redis://127.0.0.1/", "events")
+                .redis(cluster.clone())
+                .scheduled_set("{tasks}/scheduled")
+                .watcher(WatcherConf::new("{events}").rule(rule))
+                .build()
+        };
+        conf("{tasks}/todo").unwrap();
+        assert!(matches!(conf("{other}/todo"), Err(ConfError::CrossSlot(..))));
+        // the slot of a queue computed from the event can't be checked
+        assert!(matches!(conf("{tasks/${product}}/todo"), Err(ConfError::CrossSlot(..))));
+        assert!(matches!(conf("trt/${product}/todo"), Err(ConfError::CrossSlot(..))));
+    }
 }
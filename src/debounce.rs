@@ -0,0 +1,296 @@
+use {
+    crate::*,
+    log::*,
+    serde::Deserialize,
+    std::{
+        convert::TryFrom,
+        sync::Arc,
+        time::Duration,
+    },
+};
+
+/// time between two checks of the closed windows
+const DEBOUNCER_PERIOD: Duration = Duration::from_millis(500);
+
+/// max number of windows closed in one check of a rule
+const MAX_FIRES_PER_CHECK: usize = 1000;
+
+/// The debouncing configuration of a rule.
+///
+/// It's either given as just a window ("5s"), the key being
+/// then the input task, or as a window and a key pattern.
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(try_from = "DebounceDef")]
+pub struct Debounce {
+
+    /// the time between the first event of a key and the
+    /// application of the rule
+    pub window: Duration,
+
+    /// the pattern computing the key on which events are
+    /// coalesced
+    pub key: Pattern,
+
+}
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum DebounceDef {
+    Window(String),
+    Full {
+        window: String,
+        #[serde(default = "Pattern::default_task")]
+        key: Pattern,
+    },
+}
+
+impl TryFrom<DebounceDef> for Debounce {
+    type Error = ConfError;
+    fn try_from(def: DebounceDef) -> Result<Self, Self::Error> {
+        Ok(match def {
+            DebounceDef::Window(window) => Self {
+                window: parse_duration(&window)?,
+                key: Pattern::default_task(),
+            },
+            DebounceDef::Full { window, key } => Self {
+                window: parse_duration(&window)?,
+                key,
+            },
+        })
+    }
+}
+
+/// The keys holding the debouncing state of a rule
+#[derive(Debug, Clone)]
+pub(crate) struct DebounceKeys {
+    /// sorted set of the keys, scored by the time their window closes
+    due: String,
+    /// hash of the last event of each key whose window is open
+    pending: String,
+    /// hash of the events whose window is closed but whose
+    /// rule application isn't finished
+    firing: String,
+}
+
+impl DebounceKeys {
+    pub(crate) fn new(input_queue: &str, rule: &Rule) -> Self {
+        let prefix = format!("resc/debounce/{}/{}", input_queue, &rule.name);
+        Self {
+            due: format!("{}/due", &prefix),
+            pending: format!("{}/pending", &prefix),
+            firing: format!("{}/firing", &prefix),
+        }
+    }
+    /// the keys, which are used together by a script
+    pub(crate) fn all(&self) -> [&str; 3] {
+        [&self.due, &self.pending, &self.firing]
+    }
+}
+
+/// The debouncer of a watcher records the events of the
+/// debounced rules, and applies those rules when the
+/// windows close.
+///
+/// As the whole state is in the backend, pending events
+/// survive a restart.
+pub struct Debouncer {
+    backend: Arc<dyn QueueBackend>,
+    dispatcher: Dispatcher,
+    taken_queue: String,
+    rules: Vec<(Rule, DebounceKeys)>,
+}
+
+impl Debouncer {
+
+    pub fn new(
+        input_queue: &str,
+        taken_queue: &str,
+        ruleset: &Ruleset,
+        dispatcher: &Dispatcher,
+        backend: &Arc<dyn QueueBackend>,
+    ) -> Self {
+        let rules = ruleset.rules.iter()
+            .filter(|rule| rule.debounce.is_some())
+            .map(|rule| (rule.clone(), DebounceKeys::new(input_queue, rule)))
+            .collect();
+        Self {
+            backend: Arc::clone(backend),
+            dispatcher: dispatcher.clone(),
+            taken_queue: taken_queue.to_owned(),
+            rules,
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.rules.is_empty()
+    }
+
+    /// record the event as the last one of its key for a
+    /// debounced rule, opening the window if it's not already open
+    pub async fn record(
+        backend: &dyn QueueBackend,
+        input_queue: &str,
+        rule: &Rule,
+        event: &str,
+    ) -> Result<(), RescError> {
+        let Some(debounce) = rule.debounce.as_ref() else {
+            return Ok(());
+        };
+        let keys = DebounceKeys::new(input_queue, rule);
+        let key = debounce.key.inject(&rule.matching_props(event)?);
+        let due = precise_now_secs() + debounce.window.as_secs_f64();
+        backend.debounce(&keys.due, &keys.pending, &key, event, due).await?;
+        debug!("  event {:?} debounced with key {:?}", event, &key);
+        Ok(())
+    }
+
+    /// apply the rules for the events whose window closed, forever
+    pub async fn run(&mut self) {
+        loop {
+            self.check().await;
+            tokio::time::sleep(DEBOUNCER_PERIOD).await;
+        }
+    }
+
+    /// apply the rules to the events whose application failed, or
+    /// was interrupted by a stop, then to the events whose window
+    /// just closed
+    async fn check(&mut self) {
+        for i in 0..self.rules.len() {
+            let checked = match self.retry_firings(i).await {
+                Ok(()) => self.close_windows(i).await,
+                Err(e) => Err(e),
+            };
+            if let Err(e) = checked {
+                error!("debounced rule {:?} failed : {}", &self.rules[i].0.name, e);
+            }
+        }
+    }
+
+    async fn retry_firings(&mut self, i: usize) -> Result<(), RescError> {
+        let firing = self.backend.hash_entries(&self.rules[i].1.firing).await?;
+        if !firing.is_empty() {
+            warn!("retrying {} debounced events of rule {:?}", firing.len(), &self.rules[i].0.name);
+        }
+        for (key, event) in firing {
+            self.fire(i, &key, &event).await?;
+        }
+        Ok(())
+    }
+
+    async fn close_windows(&mut self, i: usize) -> Result<(), RescError> {
+        let keys = &self.rules[i].1;
+        let closed = self.backend.close_windows(
+            &keys.due,
+            &keys.pending,
+            &keys.firing,
+            precise_now_secs(),
+            MAX_FIRES_PER_CHECK,
+        ).await?;
+        for (key, event) in closed {
+            self.fire(i, &key, &event).await?;
+        }
+        Ok(())
+    }
+
+    /// apply the rule to the event, which stays in the firing
+    /// hash until the resulting tasks are pushed
+    async fn fire(&mut self, i: usize, key: &str, event: &str) -> Result<(), RescError> {
+        let (rule, keys) = &self.rules[i];
+        if !rule.is_match(event) {
+            // the event was recorded by another version of the rule
+            warn!("debounced {:?} doesn't match rule {:?}, dropped", event, &rule.name);
+            self.backend.remove_from_hash(&keys.firing, key).await?;
+            return Ok(());
+        }
+        info!("<- debounced {:?} fires rule {:?}", event, &rule.name);
+        match rule.results(event).await {
+            Ok(results) => {
+                for r in results {
+                    self.dispatcher.dispatch(&r, &self.taken_queue, event, Some(&rule.name)).await?;
+                }
+            }
+            Err(e) => {
+                error!("  Rule execution failed: {:?}", e);
+            }
+        }
+        self.backend.remove_from_hash(&keys.firing, key).await?;
+        Ok(())
+    }
+
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn debounced_rule() -> Rule {
+        Rule::builder("trt", r"^acq/(?P<process>\w+)/(?P<product>\w+)$")
+            .make(Maker::new("trt/${process}", "trt/todo"))
+            .debounce(Duration::from_millis(100), "${process}")
+            .build()
+            .unwrap()
+    }
+
+    fn debouncer(rule: &Rule, memory: &MemoryBackend) -> Debouncer {
+        let conf = Conf::builder("redis://127.0.0.1/", "listener")
+            .watcher(WatcherConf::new("events").rule(rule.clone()))
+            .build()
+            .unwrap();
+        let backend: Arc<dyn QueueBackend> = Arc::new(memory.clone());
+        let ruleset = Ruleset { rules: vec![rule.clone()] };
+        let dispatcher = Dispatcher::new(&conf, Arc::clone(&backend));
+        Debouncer::new("events", "events/taken", &ruleset, &dispatcher, &backend)
+    }
+
+    #[tokio::test]
+    async fn last_event_fires_once_window_closed() {
+        let memory = MemoryBackend::new();
+        let rule = debounced_rule();
+        let mut debouncer = debouncer(&rule, &memory);
+        for event in &["acq/a/1", "acq/a/2", "acq/b/1"] {
+            Debouncer::record(&memory, "events", &rule, event).await.unwrap();
+        }
+        debouncer.check().await;
+        assert!(memory.queue("trt/todo").is_empty());
+        tokio::time::sleep(Duration::from_millis(150)).await;
+        debouncer.check().await;
+        let mut todo = memory.queue("trt/todo");
+        todo.sort();
+        assert_eq!(todo, vec!["trt/a", "trt/b"]);
+        let keys = DebounceKeys::new("events", &rule);
+        assert!(memory.set(&keys.due).is_empty());
+        assert!(memory.hash_entries(&keys.pending).await.unwrap().is_empty());
+        assert!(memory.hash_entries(&keys.firing).await.unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn firing_event_is_retried() {
+        let memory = MemoryBackend::new();
+        let rule = debounced_rule();
+        let mut debouncer = debouncer(&rule, &memory);
+        // an event whose rule application was interrupted
+        let keys = DebounceKeys::new("events", &rule);
+        memory.debounce(&keys.due, &keys.pending, "a", "acq/a/1", 0.0).await.unwrap();
+        memory.close_windows(&keys.due, &keys.pending, &keys.firing, 1.0, 10).await.unwrap();
+        assert_eq!(
+            memory.hash_entries(&keys.firing).await.unwrap(),
+            vec![("a".to_owned(), "acq/a/1".to_owned())],
+        );
+        debouncer.check().await;
+        assert_eq!(memory.queue("trt/todo"), vec!["trt/a"]);
+        assert!(memory.hash_entries(&keys.firing).await.unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn unmatched_firing_event_is_dropped() {
+        let memory = MemoryBackend::new();
+        let rule = debounced_rule();
+        let mut debouncer = debouncer(&rule, &memory);
+        let keys = DebounceKeys::new("events", &rule);
+        memory.debounce(&keys.due, &keys.pending, "x", "other/x", 0.0).await.unwrap();
+        debouncer.check().await;
+        assert!(memory.queue("trt/todo").is_empty());
+        assert!(memory.hash_entries(&keys.firing).await.unwrap().is_empty());
+    }
+}
@@ -0,0 +1,141 @@
+use {
+    crate::*,
+    log::*,
+    std::sync::Arc,
+};
+
+/// The dispatcher applies rule results, that is it pushes
+/// the generated tasks to their queues (or schedules them),
+/// and notifies the listeners.
+///
+/// It's cheap to clone, all clones sharing the same backend.
+#[derive(Clone)]
+pub struct Dispatcher {
+    backend: Arc<dyn QueueBackend>,
+    listener_channel: String,
+    listener_format: ListenerFormat,
+    scheduled_set: String,
+    lineage: Option<LineageConf>,
+    audit: Option<AuditLog>,
+    /// the input queue of the watcher, if the dispatcher is a watcher's
+    input_queue: Option<String>,
+}
+
+impl Dispatcher {
+
+    pub fn new(
+        global_conf: &Conf,
+        backend: Arc<dyn QueueBackend>,
+    ) -> Self {
+        Self {
+            backend,
+            listener_channel: global_conf.listener_channel.clone(),
+            listener_format: global_conf.listener_format,
+            scheduled_set: global_conf.scheduled_set.clone(),
+            lineage: global_conf.lineage.clone(),
+            audit: global_conf.audit.as_ref().map(AuditLog::new),
+            input_queue: None,
+        }
+    }
+
+    /// make the dispatcher record, in the lineage of tasks, that
+    /// their parent events were taken from this input queue
+    pub fn for_watcher(mut self, input_queue: &str) -> Self {
+        self.input_queue = Some(input_queue.to_owned());
+        self
+    }
+
+    /// record the lineage of the task of a rule result, if enabled
+    async fn record_lineage(
+        &mut self,
+        r: &RuleResult,
+        source: &str,
+        event: &str,
+        rule: Option<&str>,
+    ) -> Result<(), RescError> {
+        let Some(lineage_conf) = &self.lineage else {
+            return Ok(());
+        };
+        let lineage = Lineage {
+            parent_event: event.to_owned(),
+            parent_queue: self.input_queue.clone(),
+            rule: rule.map(|rule| rule.to_owned()),
+            source: source.to_owned(),
+            time: now_secs(),
+        };
+        let Some(mut con) = self.backend.redis() else {
+            return Err(RescError::RedisRequired("lineage"));
+        };
+        lineage_conf.record(&mut con, &r.queue, &r.task, &lineage).await
+    }
+
+    /// publish a message on the listener channel, if
+    /// its kind is published in the configured format,
+    /// and append it to the audit log
+    pub async fn notify(&mut self, message: ListenerMessage) -> Result<(), RescError> {
+        if let Some(audit) = &self.audit {
+            audit.append(self.backend.as_ref(), &message).await;
+        }
+        if let Some(message) = message.render(self.listener_format) {
+            self.backend.publish(&self.listener_channel, &message).await?;
+        }
+        Ok(())
+    }
+
+    /// push the task of a rule result to its queue, unless it's
+    /// already in the task set, or schedule it if it's delayed.
+    ///
+    /// `source` is the queue where the triggering event was found,
+    /// and `rule` the name of the rule which produced the result.
+    pub async fn dispatch(
+        &mut self,
+        r: &RuleResult,
+        source: &str,
+        event: &str,
+        rule: Option<&str>,
+    ) -> Result<(), RescError> {
+        let now = now_secs();
+        let message = |kind| ListenerMessage {
+            event: Some(event.to_owned()),
+            rule: rule.map(|rule| rule.to_owned()),
+            task: Some(r.task.clone()),
+            queue: Some(r.queue.clone()),
+            set: r.set.clone(),
+            ..ListenerMessage::new(kind, source)
+        };
+        if let Some(due) = r.due.filter(|&due| due > now) {
+            // the task set is checked again when the task is due
+            info!("  ->  {:?} scheduled for queue {:?} @ {}", &r.task, &r.queue, due);
+            ScheduledTask::from_result(r)
+                .schedule(self.backend.as_ref(), &self.scheduled_set, due)
+                .await?;
+            self.record_lineage(r, source, event, rule).await?;
+            return self.notify(ListenerMessage {
+                due: Some(due),
+                ..message(ListenerEvent::Scheduled)
+            }).await;
+        }
+        if let Some(task_set) = r.set.as_ref() {
+            // if the rule specifies a task_set, the task is only pushed if
+            // it's not already in the set. The check, the addition to the
+            // set and the push are atomic, so that two handlings making
+            // the same task don't both push it
+            if !self.backend.push_deduplicated(&r.queue, task_set, &r.task, now).await? {
+                info!("  task {:?} already queued", &r.task);
+                METRICS.dedup_skips.inc(&[&r.queue]);
+                return self.notify(message(ListenerEvent::DedupSkipped)).await;
+            }
+            debug!(
+                "      {:?} pushed to task_set {:?} @ {}",
+                &r.task, task_set, now
+            );
+        } else {
+            self.backend.push(&r.queue, &r.task).await?;
+        }
+        info!("  ->  {:?} pushed to queue {:?}", &r.task, &r.queue);
+        self.record_lineage(r, source, event, rule).await?;
+        METRICS.tasks_pushed.inc(&[&r.queue]);
+        self.notify(message(ListenerEvent::Pushed)).await
+    }
+
+}
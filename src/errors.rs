@@ -15,6 +15,45 @@ pub enum RescError {
     #[error("redis error")]
     Redis(#[from] redis::RedisError),
 
+    #[error("invalid time: {0:?}")]
+    InvalidTime(String),
+
+    #[error("invalid attempt number: {0:?}")]
+    InvalidAttempt(String),
+
+    #[error("IO error: {0}")]
+    IO(#[from] std::io::Error),
+
+    #[error("no configuration file to reload")]
+    NoConfFile,
+
+    #[error("{0} needs the Redis backend")]
+    RedisRequired(&'static str),
+
+    #[error("rule {0:?} doesn't match {1:?}")]
+    NotMatching(String, String),
+
+    #[error("worker loop on {0:?} panicked: {1}")]
+    WorkerPanic(String, String),
+
+}
+
+impl RescError {
+    /// whether the error is due to the connection to the backend
+    /// being lost, in which case a new connection is needed
+    pub fn is_connection_loss(&self) -> bool {
+        match self {
+            Self::Redis(e) => {
+                e.is_unrecoverable_error()
+                    || e.is_connection_dropped()
+                    || e.is_connection_refusal()
+                    || e.is_timeout()
+                    // a master demoted to replica by a failover rejects writes
+                    || e.kind() == redis::ErrorKind::ReadOnly
+            }
+            _ => false,
+        }
+    }
 }
 
 #[derive(Error, Debug)]
@@ -31,6 +70,39 @@ pub enum ConfError {
 
     #[error("Invalid JSON: {0}")]
     JSON(#[from] serde_json::Error),
+
+    #[error("Invalid duration: {0:?}")]
+    InvalidDuration(String),
+
+    #[error("Invalid schedule {0:?}: it needs either an event and a queue, or a make element")]
+    InvalidSchedule(String),
+
+    #[error("Several schedules are named {0:?}")]
+    DuplicateSchedule(String),
+
+    #[error("Several debounced rules of a watcher are named {0:?}")]
+    DuplicateDebouncedRule(String),
+
+    #[error("Invalid workflow {0:?}: {1}")]
+    InvalidWorkflow(String, String),
+
+    #[error("Invalid regex {0:?}: {1}")]
+    InvalidRegex(String, String),
+
+    #[error("Rule {0:?} makes no task")]
+    NoMaker(String),
+
+    #[error("The watcher on {0:?} needs at least one partition")]
+    NoPartition(String),
+
+    #[error("Invalid Redis configuration: {0}")]
+    InvalidRedis(String),
+
+    #[error("Unreadable secret: {0}")]
+    UnreadableSecret(String),
+
+    #[error("Keys {0:?} and {1:?} are used together, so they need the same hash tag in a Redis Cluster")]
+    CrossSlot(String, String),
 }
 
 
@@ -40,6 +112,9 @@ pub enum FetchError {
     #[error("reqwest error")]
     Reqwest(#[from] reqwest::Error),
 
+    #[error("fetch timed out after {0:?}")]
+    Timeout(std::time::Duration),
+
     #[error("fetch received an error - status: {0}")]
     ErrorStatus(u16),
 
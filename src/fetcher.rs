@@ -1,26 +1,62 @@
 use {
     crate::*,
+    lazy_static::lazy_static,
     log::*,
     serde::Deserialize,
     serde_json::{self, Value},
-    std::{collections::HashMap, io::Read},
+    std::{
+        collections::HashMap,
+        time::{Duration, Instant},
+    },
 };
 
+lazy_static! {
+    /// the HTTP client shared by all fetchers, so that
+    /// connections to a server are pooled and reused
+    static ref HTTP_CLIENT: reqwest::Client = reqwest::Client::builder()
+        .timeout(Fetcher::default_timeout())
+        .build()
+        .expect("HTTP client");
+}
+
 /// the data the fetcher got
 #[derive(Debug)]
 pub struct FetchResult {
     pub props: HashMap<String, String>,
 }
 
+/// what a fetcher queried, and how many results it got
+#[derive(Debug)]
+pub struct Fetched {
+    pub url: String,
+    pub count: usize,
+}
+
 /// A Fetcher is responsible for synchronously fetching some data
 /// (for use in handling a rule)
-#[derive(Debug, Clone, Deserialize)]
+#[derive(Debug, Clone, PartialEq, Deserialize)]
 pub struct Fetcher {
     pub url: Pattern,
     pub returns: String,
+    /// the max duration of the query, after which it fails
+    #[serde(default = "Fetcher::default_timeout", deserialize_with = "deserialize_duration")]
+    pub timeout: Duration,
 }
 
 impl Fetcher {
+    pub fn default_timeout() -> Duration {
+        Duration::from_secs(30)
+    }
+
+    /// the error of a query, telling timeouts apart
+    fn query_error(&self, e: reqwest::Error) -> FetchError {
+        if e.is_timeout() {
+            FetchError::Timeout(self.timeout)
+        } else {
+            FetchError::Reqwest(e)
+        }
+    }
+
     fn returned_key(&self, key: &str) -> String {
         format!("{}.{}", self.returns, key)
     }
@@ -43,16 +79,29 @@ impl Fetcher {
         FetchResult { props }
     }
 
-    pub fn results(&self, props: &HashMap<String, String>) -> Result<Vec<FetchResult>, FetchError> {
+    pub async fn results(&self, props: &HashMap<String, String>) -> Result<Vec<FetchResult>, FetchError> {
+        let start = Instant::now();
+        let results = self.fetch_results(props).await;
+        METRICS.fetch_duration.observe(&[&self.url.src], start.elapsed());
+        if results.is_err() {
+            METRICS.fetch_errors.inc(&[&self.url.src]);
+        }
+        results
+    }
+
+    async fn fetch_results(&self, props: &HashMap<String, String>) -> Result<Vec<FetchResult>, FetchError> {
         let url = self.url.inject(props);
         info!("  querying url: {:#?}", url);
-        let mut response = reqwest::get(&url)?;
+        let response = HTTP_CLIENT.get(&url)
+            .timeout(self.timeout)
+            .send()
+            .await
+            .map_err(|e| self.query_error(e))?;
         if !response.status().is_success() {
             return Err(FetchError::ErrorStatus(response.status().into()));
         }
         // TODO use derive for response deserialization
-        let mut json = String::new();
-        response.read_to_string(&mut json)?;
+        let json = response.text().await.map_err(|e| self.query_error(e))?;
         let mut results = Vec::new();
         let value: Value = serde_json::from_str(&json)?;
         // we accept either a simple object, or an array of objects
@@ -79,3 +128,33 @@ impl Fetcher {
         Ok(results)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn unanswered_query_times_out() {
+        // a server accepting connections but never answering
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}/slow", listener.local_addr().unwrap());
+        let server = tokio::spawn(async move {
+            let mut connections = Vec::new();
+            while let Ok((connection, _)) = listener.accept().await {
+                connections.push(connection);
+            }
+        });
+        let fetcher = Fetcher {
+            url: Pattern::from(url.as_str()),
+            returns: "slow".to_owned(),
+            timeout: Duration::from_millis(200),
+        };
+        let start = Instant::now();
+        let results = fetcher.results(&HashMap::new()).await;
+        assert!(matches!(results, Err(FetchError::Timeout(_))));
+        assert!(start.elapsed() < Duration::from_secs(5));
+        let errors = format!("resc_fetch_errors_total{{fetcher=\"{}\"}} 1", url);
+        assert!(METRICS.render().lines().any(|line| line == errors));
+        server.abort();
+    }
+}
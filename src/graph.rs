@@ -0,0 +1,212 @@
+use {
+    crate::*,
+    std::io::{self, Write},
+};
+
+/// Formats in which the graph can be written
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum GraphFormat {
+    Dot,
+    Mermaid,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum NodeKind {
+    /// a queue whose name is known
+    Queue,
+    /// a queue name pattern, with variables
+    QueuePattern,
+    Rule,
+    Schedule,
+}
+
+#[derive(Debug)]
+struct Node {
+    id: String,
+    label: String,
+    kind: NodeKind,
+}
+
+#[derive(Debug)]
+struct Edge {
+    from: String,
+    to: String,
+    label: Option<String>,
+    /// whether the edge is a possible link between an output
+    /// queue pattern and an input queue
+    possible: bool,
+}
+
+/// The topology of a configuration: the queues, the rules
+/// and the schedules, and how tasks flow between them
+#[derive(Debug, Default)]
+pub struct Graph {
+    nodes: Vec<Node>,
+    edges: Vec<Edge>,
+}
+
+impl Graph {
+
+    pub fn new(conf: &Conf) -> Self {
+        let mut graph = Self::default();
+        for watcher in &conf.watchers {
+            let input = graph.queue_node(&watcher.input_queue);
+            for rule in &watcher.rules {
+                let mut label = rule.name.clone();
+                for fetcher in &rule.fetchers {
+                    label.push_str(&format!("\nfetch {}", &fetcher.url.src));
+                }
+                let rule_node = graph.add_node(label, NodeKind::Rule);
+                graph.add_edge(&input, &rule_node, None, false);
+                graph.add_makers(&rule_node, &rule.makers, &rule.name);
+            }
+        }
+        for schedule in &conf.schedules {
+            let schedule_node = graph.add_node(
+                format!("{}\n{}", &schedule.name, &schedule.cron),
+                NodeKind::Schedule,
+            );
+            if let Some(queue) = &schedule.queue {
+                let queue = graph.queue_node(&queue.src);
+                graph.add_edge(&schedule_node, &queue, None, false);
+            }
+            if let Some(makers) = &schedule.makers {
+                graph.add_makers(&schedule_node, makers, &schedule.name);
+            }
+        }
+        graph.link_patterns_to_input_queues(conf);
+        graph
+    }
+
+    fn add_node(&mut self, label: String, kind: NodeKind) -> String {
+        let id = format!("n{}", self.nodes.len());
+        self.nodes.push(Node { id: id.clone(), label, kind });
+        id
+    }
+
+    /// return the id of the node of a queue or queue pattern,
+    /// creating it if needed
+    fn queue_node(&mut self, name: &str) -> String {
+        let kind = if Pattern::from(name).variables().is_empty() {
+            NodeKind::Queue
+        } else {
+            NodeKind::QueuePattern
+        };
+        match self.nodes.iter().find(|n| n.kind == kind && n.label == name) {
+            Some(node) => node.id.clone(),
+            None => self.add_node(name.to_owned(), kind),
+        }
+    }
+
+    fn add_edge(&mut self, from: &str, to: &str, label: Option<String>, possible: bool) {
+        self.edges.push(Edge {
+            from: from.to_owned(),
+            to: to.to_owned(),
+            label,
+            possible,
+        });
+    }
+
+    fn add_makers(&mut self, from: &str, makers: &Makers, label: &str) {
+        match makers {
+            Makers::Single(maker) => {
+                let queue = self.queue_node(&maker.queue.src);
+                self.add_edge(from, &queue, Some(label.to_owned()), false);
+            }
+            Makers::Multiple(vec) => {
+                for maker in vec {
+                    let queue = self.queue_node(&maker.queue.src);
+                    let label = match &maker.name {
+                        Some(name) => format!("{} / {}", label, name),
+                        None => label.to_owned(),
+                    };
+                    self.add_edge(from, &queue, Some(label), false);
+                }
+            }
+        }
+    }
+
+    /// add the edges from the output queue patterns to the input
+    /// queues they can produce
+    fn link_patterns_to_input_queues(&mut self, conf: &Conf) {
+        let mut links = Vec::new();
+        for node in self.nodes.iter().filter(|n| n.kind == NodeKind::QueuePattern) {
+            let Ok(regex) = Pattern::from(node.label.as_str()).to_regex() else {
+                continue;
+            };
+            for watcher in &conf.watchers {
+                if regex.is_match(&watcher.input_queue) {
+                    links.push((node.id.clone(), watcher.input_queue.clone()));
+                }
+            }
+        }
+        for (from, input_queue) in links {
+            let to = self.queue_node(&input_queue);
+            self.add_edge(&from, &to, None, true);
+        }
+    }
+
+    pub fn write<W: Write>(&self, w: &mut W, format: GraphFormat) -> io::Result<()> {
+        match format {
+            GraphFormat::Dot => self.write_dot(w),
+            GraphFormat::Mermaid => self.write_mermaid(w),
+        }
+    }
+
+    pub fn write_dot<W: Write>(&self, w: &mut W) -> io::Result<()> {
+        fn escape(s: &str) -> String {
+            s.replace('\\', "\\\\").replace('"', "\\\"").replace('\n', "\\n")
+        }
+        writeln!(w, "digraph resc {{")?;
+        writeln!(w, "    rankdir=LR;")?;
+        for node in &self.nodes {
+            let style = match node.kind {
+                NodeKind::Queue => "shape=box",
+                NodeKind::QueuePattern => "shape=box, style=dashed",
+                NodeKind::Rule => "shape=ellipse",
+                NodeKind::Schedule => "shape=hexagon",
+            };
+            writeln!(w, "    {} [label=\"{}\", {}];", node.id, escape(&node.label), style)?;
+        }
+        for edge in &self.edges {
+            let mut attributes = Vec::new();
+            if let Some(label) = &edge.label {
+                attributes.push(format!("label=\"{}\"", escape(label)));
+            }
+            if edge.possible {
+                attributes.push("style=dotted".to_string());
+            }
+            if attributes.is_empty() {
+                writeln!(w, "    {} -> {};", edge.from, edge.to)?;
+            } else {
+                writeln!(w, "    {} -> {} [{}];", edge.from, edge.to, attributes.join(", "))?;
+            }
+        }
+        writeln!(w, "}}")
+    }
+
+    pub fn write_mermaid<W: Write>(&self, w: &mut W) -> io::Result<()> {
+        fn escape(s: &str) -> String {
+            s.replace('"', "#quot;").replace('\n', "<br>")
+        }
+        writeln!(w, "flowchart LR")?;
+        for node in &self.nodes {
+            let label = escape(&node.label);
+            match node.kind {
+                NodeKind::Queue => writeln!(w, "    {}[\"{}\"]", node.id, label)?,
+                NodeKind::QueuePattern => writeln!(w, "    {}[/\"{}\"/]", node.id, label)?,
+                NodeKind::Rule => writeln!(w, "    {}([\"{}\"])", node.id, label)?,
+                NodeKind::Schedule => writeln!(w, "    {}{{{{\"{}\"}}}}", node.id, label)?,
+            }
+        }
+        for edge in &self.edges {
+            let arrow = if edge.possible { "-.->" } else { "-->" };
+            match &edge.label {
+                Some(label) => writeln!(w, "    {} {}|\"{}\"| {}", edge.from, arrow, escape(label), edge.to)?,
+                None => writeln!(w, "    {} {} {}", edge.from, arrow, edge.to)?,
+            }
+        }
+        Ok(())
+    }
+
+}
@@ -0,0 +1,174 @@
+use {
+    log::*,
+    std::{
+        future::Future,
+        io,
+        time::Duration,
+    },
+    tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::{TcpListener, TcpStream},
+    },
+};
+
+/// max size of the head of a request
+const MAX_HEAD_SIZE: usize = 8 * 1024;
+
+/// max time to receive the head of a request
+const READ_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// time to wait before accepting connections again after a
+/// failure, which is usually a lack of file descriptors
+const ACCEPT_RETRY_DELAY: Duration = Duration::from_millis(100);
+
+/// The part of a HTTP request resc cares about
+#[derive(Debug)]
+pub struct HttpRequest {
+    pub method: String,
+    pub path: String,
+}
+
+#[derive(Debug)]
+pub struct HttpResponse {
+    pub status: u16,
+    pub content_type: &'static str,
+    pub body: String,
+}
+
+impl HttpResponse {
+    pub fn ok(content_type: &'static str, body: String) -> Self {
+        Self { status: 200, content_type, body }
+    }
+    pub fn text(status: u16, body: &str) -> Self {
+        Self {
+            status,
+            content_type: "text/plain; charset=utf-8",
+            body: format!("{}\n", body),
+        }
+    }
+    pub fn not_found() -> Self {
+        Self::text(404, "not found")
+    }
+    fn reason(&self) -> &'static str {
+        match self.status {
+            200 => "OK",
+            202 => "Accepted",
+            400 => "Bad Request",
+            404 => "Not Found",
+            405 => "Method Not Allowed",
+            409 => "Conflict",
+            _ => "Internal Server Error",
+        }
+    }
+}
+
+/// A minimal HTTP/1.1 server, enough for the few endpoints
+/// resc exposes. Every connection handles one request.
+///
+/// Only the failure to listen is returned: failures to accept
+/// a connection are logged and the server goes on.
+pub async fn serve<H, F>(address: &str, handler: H) -> io::Result<()>
+where
+    H: Fn(HttpRequest) -> F + Clone + Send + 'static,
+    F: Future<Output = HttpResponse> + Send,
+{
+    let listener = TcpListener::bind(address).await?;
+    info!("HTTP server listening on {}", address);
+    loop {
+        let stream = match listener.accept().await {
+            Ok((stream, _)) => stream,
+            Err(e) => {
+                warn!("HTTP server on {} can't accept a connection: {}", address, e);
+                tokio::time::sleep(ACCEPT_RETRY_DELAY).await;
+                continue;
+            }
+        };
+        let handler = handler.clone();
+        tokio::spawn(async move {
+            if let Err(e) = handle_connection(stream, handler).await {
+                debug!("HTTP connection failed: {}", e);
+            }
+        });
+    }
+}
+
+async fn handle_connection<H, F>(mut stream: TcpStream, handler: H) -> io::Result<()>
+where
+    H: Fn(HttpRequest) -> F,
+    F: Future<Output = HttpResponse>,
+{
+    let mut head = Vec::new();
+    let mut buf = [0; 1024];
+    let read_deadline = tokio::time::Instant::now() + READ_TIMEOUT;
+    while !head.windows(4).any(|w| w == b"\r\n\r\n") {
+        let n = tokio::time::timeout_at(read_deadline, stream.read(&mut buf))
+            .await
+            .map_err(|_| io::Error::new(io::ErrorKind::TimedOut, "request head not received"))??;
+        if n == 0 || head.len() + n > MAX_HEAD_SIZE {
+            return Ok(());
+        }
+        head.extend_from_slice(&buf[..n]);
+    }
+    let head = String::from_utf8_lossy(&head);
+    let mut parts = head.lines().next().unwrap_or_default().split_whitespace();
+    let response = match (parts.next(), parts.next()) {
+        (Some(method), Some(path)) => {
+            handler(HttpRequest {
+                method: method.to_owned(),
+                path: path.to_owned(),
+            }).await
+        }
+        _ => HttpResponse::text(400, "bad request"),
+    };
+    let head = format!(
+        "HTTP/1.1 {} {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+        response.status,
+        response.reason(),
+        response.content_type,
+        response.body.len(),
+    );
+    stream.write_all(head.as_bytes()).await?;
+    stream.write_all(response.body.as_bytes()).await?;
+    stream.shutdown().await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    async fn query(address: &str, request: &str) -> String {
+        let mut stream = TcpStream::connect(address).await.unwrap();
+        stream.write_all(request.as_bytes()).await.unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).await.unwrap();
+        response
+    }
+
+    #[tokio::test]
+    async fn requests_are_answered() {
+        let address = {
+            let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+            listener.local_addr().unwrap().to_string()
+        };
+        let served = address.clone();
+        let server = tokio::spawn(async move {
+            serve(&served, |request: HttpRequest| async move {
+                match (request.method.as_str(), request.path.as_str()) {
+                    ("GET", "/hello") => HttpResponse::text(200, "hello"),
+                    _ => HttpResponse::not_found(),
+                }
+            }).await
+        });
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        // a silent connection doesn't prevent answering the other ones
+        let _silent = TcpStream::connect(&address).await.unwrap();
+        let response = query(&address, "GET /hello HTTP/1.1\r\nHost: test\r\n\r\n").await;
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"));
+        assert!(response.ends_with("\r\n\r\nhello\n"));
+        let response = query(&address, "GET /other HTTP/1.1\r\n\r\n").await;
+        assert!(response.starts_with("HTTP/1.1 404 Not Found\r\n"));
+        let response = query(&address, "nonsense\r\n\r\n").await;
+        assert!(response.starts_with("HTTP/1.1 400 Bad Request\r\n"));
+        server.abort();
+    }
+}
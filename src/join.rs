@@ -0,0 +1,158 @@
+use {
+    crate::*,
+    log::*,
+    serde::Deserialize,
+    std::{
+        collections::HashMap,
+        time::Duration,
+    },
+};
+
+/// The join configuration of a rule: the rule only applies
+/// when all the expected events arrived.
+///
+/// Arrivals are recorded under a key (a hash, with Redis) given
+/// by a pattern, so that there's one join per value of the
+/// variables in this pattern.
+///
+/// The join is only forgotten once the results of the event
+/// completing it are dispatched, so that this event, when
+/// replayed after a failure, completes it again.
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+pub struct Join {
+
+    /// the pattern of the key keeping the
+    /// arrivals, for example "resc/join/merge/${p}"
+    pub key: Pattern,
+
+    /// the pattern identifying the arrival among the expected
+    /// ones (by default the input task)
+    #[serde(default = "Pattern::default_task")]
+    pub member: Pattern,
+
+    /// the patterns of the expected members
+    pub expect: Vec<Pattern>,
+
+    /// the time after which an incomplete join is forgotten,
+    /// measured from the last arrival
+    #[serde(default = "Join::default_ttl", deserialize_with = "deserialize_duration")]
+    pub ttl: Duration,
+
+}
+
+impl Join {
+    pub fn default_ttl() -> Duration {
+        Duration::from_secs(24 * 60 * 60)
+    }
+    /// the key of the join and the member of the event
+    fn key_and_member(
+        &self,
+        rule: &Rule,
+        event: &str,
+    ) -> Result<(String, String, HashMap<String, String>), RescError> {
+        let props = rule.matching_props(event)?;
+        let key = self.key.inject(&props);
+        let member = self.member.inject(&props);
+        Ok((key, member, props))
+    }
+    /// record the arrival of the event and return whether
+    /// it completes the join
+    pub async fn arrive(
+        &self,
+        backend: &dyn QueueBackend,
+        rule: &Rule,
+        event: &str,
+    ) -> Result<bool, RescError> {
+        let (key, member, props) = self.key_and_member(rule, event)?;
+        let expected: Vec<String> = self.expect.iter()
+            .map(|pattern| pattern.inject(&props))
+            .collect();
+        if !expected.contains(&member) {
+            debug!("  {:?} isn't expected by join {:?}", &member, &key);
+            return Ok(false);
+        }
+        let complete = backend.arrive_in_join(&key, &member, &expected, self.ttl).await?;
+        if complete {
+            debug!("  {:?} completes join {:?}", &member, &key);
+        } else {
+            debug!("  {:?} recorded in join {:?}", &member, &key);
+        }
+        Ok(complete)
+    }
+    /// forget the join the event completed, once the
+    /// results of the rule are dispatched
+    pub async fn complete(
+        &self,
+        backend: &dyn QueueBackend,
+        rule: &Rule,
+        event: &str,
+    ) -> Result<(), RescError> {
+        let (key, member, _) = self.key_and_member(rule, event)?;
+        backend.complete_join(&key, &member).await
+    }
+    /// let the next arrival complete the join again, when the
+    /// rule failed on the event which completed it
+    pub async fn release(
+        &self,
+        backend: &dyn QueueBackend,
+        rule: &Rule,
+        event: &str,
+    ) -> Result<(), RescError> {
+        let (key, member, _) = self.key_and_member(rule, event)?;
+        backend.release_join(&key, &member).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn merge_rule() -> Rule {
+        Rule::builder("merge", r"^(?P<step>a|b)/(?P<p>\w+)$")
+            .join(Join::new("resc/join/merge/${p}", &["a/${p}", "b/${p}"]))
+            .make(Maker::new("merge/${p}", "merge/todo"))
+            .build()
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn partial_arrival_doesnt_complete() {
+        let backend = MemoryBackend::new();
+        let rule = merge_rule();
+        let join = rule.join.as_ref().unwrap();
+        assert!(!join.arrive(&backend, &rule, "a/1").await.unwrap());
+        assert!(!join.arrive(&backend, &rule, "a/1").await.unwrap());
+        assert!(!join.arrive(&backend, &rule, "b/2").await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn completion_is_replayed_until_completed() {
+        let backend = MemoryBackend::new();
+        let rule = merge_rule();
+        let join = rule.join.as_ref().unwrap();
+        assert!(!join.arrive(&backend, &rule, "a/1").await.unwrap());
+        assert!(join.arrive(&backend, &rule, "b/1").await.unwrap());
+        // another arrival doesn't complete the join a second time
+        assert!(!join.arrive(&backend, &rule, "a/1").await.unwrap());
+        // but the completing event does, when replayed
+        assert!(join.arrive(&backend, &rule, "b/1").await.unwrap());
+        join.complete(&backend, &rule, "b/1").await.unwrap();
+        // the join is forgotten, a new one starts
+        assert!(!join.arrive(&backend, &rule, "b/1").await.unwrap());
+        assert!(join.arrive(&backend, &rule, "a/1").await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn released_join_is_completed_by_next_arrival() {
+        let backend = MemoryBackend::new();
+        let rule = merge_rule();
+        let join = rule.join.as_ref().unwrap();
+        assert!(!join.arrive(&backend, &rule, "a/1").await.unwrap());
+        assert!(join.arrive(&backend, &rule, "b/1").await.unwrap());
+        join.release(&backend, &rule, "b/1").await.unwrap();
+        assert!(join.arrive(&backend, &rule, "a/1").await.unwrap());
+        // only the completer completes the join
+        join.complete(&backend, &rule, "b/1").await.unwrap();
+        assert!(!join.arrive(&backend, &rule, "b/1").await.unwrap());
+    }
+}
@@ -0,0 +1,114 @@
+//! Resc is a task orchestrator for distributed systems
+//! It's based on Rust and ensures in a safe way the
+//! generation of deduced tasks and their availability
+//! for external workers
+//!
+//! This library lets you embed the orchestrator in your own
+//! service: build a [Conf], either by reading a file with
+//! [read_file] or with [Conf::builder], then start it with
+//! [Runner::start]. Rules can also be built and applied
+//! without Redis, which is convenient for testing rule sets.
+//!
+//! Introduction and complete description in the [README](https://github.com/Canop/resc)
+
+mod admin;
+mod audit;
+mod backend;
+mod builder;
+mod command_worker;
+mod conf;
+mod debounce;
+mod dispatcher;
+mod errors;
+mod fetcher;
+mod graph;
+mod http;
+mod join;
+mod lineage;
+mod listener;
+mod lock;
+mod logger;
+mod make;
+mod memory_backend;
+mod metrics;
+mod pattern;
+mod reaper;
+mod redis_backend;
+mod redis_conf;
+mod redis_connection;
+mod retry;
+mod rule;
+mod ruleset;
+mod rule_result;
+mod runner;
+mod schedule;
+mod scheduler;
+mod serde_format;
+mod sharding;
+mod time;
+mod watcher;
+mod watcher_status;
+mod worker;
+mod workflow;
+
+// the items of the modules which aren't in the public API
+// are available to the other modules with `use crate::*`
+use {
+    audit::*,
+    backend::*,
+    debounce::*,
+    dispatcher::*,
+    http::*,
+    lock::*,
+    metrics::*,
+    reaper::*,
+    redis_conf::*,
+    redis_connection::*,
+    schedule::*,
+    scheduler::*,
+    serde_format::*,
+    sharding::*,
+    time::*,
+};
+
+pub use {
+    admin::{serve_admin, AdminConf},
+    audit::AuditConf,
+    backend::{EventTaker, QueueBackend},
+    builder::{ConfBuilder, RuleBuilder},
+    command_worker::CommandWorkerConf,
+    conf::{read_file, Conf},
+    debounce::Debounce,
+    errors::{ConfError, FetchError, RescError},
+    fetcher::{FetchResult, Fetched, Fetcher},
+    graph::{Graph, GraphFormat},
+    join::Join,
+    lineage::{trace_task, Lineage, LineageConf},
+    listener::{ListenerEvent, ListenerFormat, ListenerMessage, LISTENER_MESSAGE_VERSION},
+    lock::LockConf,
+    logger::configure_logger,
+    make::{Maker, Makers},
+    memory_backend::MemoryBackend,
+    metrics::{serve_metrics, MetricsConf},
+    pattern::Pattern,
+    reaper::ReaperConf,
+    redis_backend::RedisBackend,
+    redis_conf::{ClusterConf, RedisConf, Secret, SentinelConf, TlsConf},
+    redis_connection::RedisConnection,
+    retry::{failed_event, parse_failed_event, Retry, FAILED_EVENT_PREFIX},
+    rule::Rule,
+    rule_result::RuleResult,
+    ruleset::Ruleset,
+    runner::Runner,
+    schedule::ScheduleConf,
+    watcher::{Watcher, WatcherConf},
+    watcher_status::{WatcherControl, WatcherState, WatcherStatus},
+    worker::{Worker, WorkerConf},
+    workflow::{StageConf, WorkflowConf},
+};
+
+/// What the resc binaries use beyond the public API
+#[doc(hidden)]
+pub mod internal {
+    pub use crate::serde_format::{SerdeFormat, FORMATS};
+}
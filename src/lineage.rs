@@ -0,0 +1,216 @@
+use {
+    crate::*,
+    redis::{AsyncCommands},
+    serde::{Deserialize, Serialize},
+    std::{
+        collections::HashMap,
+        io::{self, Write},
+        time::Duration,
+    },
+};
+
+/// max number of ancestors looked for when tracing a task, in
+/// case of a cycle of rules
+const MAX_TRACE_DEPTH: usize = 100;
+
+/// Configuration of the recording of where tasks come from
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+pub struct LineageConf {
+    /// how long the lineage of a task is kept
+    #[serde(
+        default = "LineageConf::default_ttl",
+        deserialize_with = "deserialize_duration",
+    )]
+    pub ttl: Duration,
+    #[serde(default = "LineageConf::default_key_prefix")]
+    pub key_prefix: String,
+}
+
+impl LineageConf {
+    pub fn default_ttl() -> Duration {
+        Duration::from_secs(7 * 24 * 60 * 60)
+    }
+    pub fn default_key_prefix() -> String {
+        "resc/lineage".to_owned()
+    }
+    /// the hash holding the lineages of a task, one per queue
+    fn key(&self, task: &str) -> String {
+        format!("{}/{}", self.key_prefix, task)
+    }
+    /// record where a task pushed or scheduled to a queue comes from
+    pub async fn record(
+        &self,
+        con: &mut RedisConnection,
+        queue: &str,
+        task: &str,
+        lineage: &Lineage,
+    ) -> Result<(), RescError> {
+        let key = self.key(task);
+        let _: () = redis::pipe()
+            .atomic()
+            .hset(&key, queue, serde_json::to_string(lineage).unwrap())
+            .pexpire(&key, self.ttl.as_millis() as i64)
+            .query_async(con)
+            .await?;
+        Ok(())
+    }
+    /// get the lineages of a task, per queue
+    pub async fn lineages(
+        &self,
+        con: &mut RedisConnection,
+        task: &str,
+    ) -> Result<HashMap<String, Lineage>, RescError> {
+        let values: HashMap<String, String> = con.hgetall(self.key(task)).await?;
+        Ok(values.into_iter()
+            .filter_map(|(queue, value)| {
+                serde_json::from_str(&value).ok().map(|lineage| (queue, lineage))
+            })
+            .collect())
+    }
+}
+
+/// Where a task comes from
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Lineage {
+    /// the event the task was made from
+    pub parent_event: String,
+    /// the input queue of the watcher which took the parent
+    /// event, absent when the parent isn't an event of a queue
+    /// (for example the tick of a schedule)
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub parent_queue: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub rule: Option<String>,
+    /// the taken queue of the watcher, or the key of the schedule
+    pub source: String,
+    /// when the task was made, in seconds since the Epoch
+    pub time: f64,
+}
+
+/// One step of a trace: a task, the queue it was pushed to
+/// and where it comes from, if known
+struct TraceStep {
+    task: String,
+    queue: String,
+    lineage: Option<Lineage>,
+}
+
+/// walk back from a task to its root event, writing every step
+///
+/// As a task may be made for several queues, there's one trace
+/// per queue.
+pub async fn trace_task<W: Write>(
+    con: &mut RedisConnection,
+    lineage_conf: &LineageConf,
+    task: &str,
+    w: &mut W,
+) -> Result<(), RescError> {
+    let lineages = lineage_conf.lineages(con, task).await?;
+    if lineages.is_empty() {
+        writeln!(w, "no lineage found for {:?}", task)?;
+        return Ok(());
+    }
+    let mut queues: Vec<&String> = lineages.keys().collect();
+    queues.sort();
+    for queue in queues {
+        let mut steps = vec![TraceStep {
+            task: task.to_owned(),
+            queue: queue.clone(),
+            lineage: lineages.get(queue).cloned(),
+        }];
+        while steps.len() < MAX_TRACE_DEPTH {
+            let Some(lineage) = &steps[steps.len() - 1].lineage else {
+                break;
+            };
+            let Some(parent_queue) = &lineage.parent_queue else {
+                break;
+            };
+            let parent_task = lineage.parent_event.clone();
+            let parent_queue = parent_queue.clone();
+            let parent_lineages = lineage_conf.lineages(con, &parent_task).await?;
+            let parent_lineage = select_lineage(parent_lineages, &parent_queue);
+            steps.push(TraceStep {
+                task: parent_task,
+                queue: parent_queue,
+                lineage: parent_lineage,
+            });
+        }
+        write_trace(&steps, w)?;
+    }
+    Ok(())
+}
+
+/// choose, among the lineages of a task, the one of the task
+/// taken from the given queue.
+///
+/// When the task wasn't recorded as pushed to that queue, for
+/// example because it was pushed by a worker or moved to a
+/// partition, the most recent lineage is chosen.
+fn select_lineage(
+    mut lineages: HashMap<String, Lineage>,
+    queue: &str,
+) -> Option<Lineage> {
+    if let Some(lineage) = lineages.remove(queue) {
+        return Some(lineage);
+    }
+    lineages.into_values()
+        .max_by(|a, b| a.time.total_cmp(&b.time))
+}
+
+fn write_trace<W: Write>(steps: &[TraceStep], w: &mut W) -> io::Result<()> {
+    for step in steps {
+        writeln!(w, "{:?} in {:?}", &step.task, &step.queue)?;
+        match &step.lineage {
+            Some(lineage) => {
+                let time = chrono::DateTime::from_timestamp(lineage.time as i64, 0)
+                    .map(|date| date.to_rfc3339())
+                    .unwrap_or_default();
+                let rule = lineage.rule.as_deref().unwrap_or("-");
+                writeln!(w, "  <- rule {:?} on {:?} @ {}", rule, &lineage.source, time)?;
+                if lineage.parent_queue.is_none() {
+                    writeln!(w, "{:?} (root)", &lineage.parent_event)?;
+                }
+            }
+            None => {
+                writeln!(w, "  (root event)")?;
+            }
+        }
+    }
+    writeln!(w)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn lineage(parent_event: &str, time: f64) -> Lineage {
+        Lineage {
+            parent_event: parent_event.to_owned(),
+            parent_queue: None,
+            rule: None,
+            source: "global/taken".to_owned(),
+            time,
+        }
+    }
+
+    #[test]
+    fn select_lineage_of_queue() {
+        let lineages: HashMap<String, Lineage> = vec![
+            ("trt/todo".to_owned(), lineage("acq/1", 10.0)),
+            ("trt/other".to_owned(), lineage("acq/2", 20.0)),
+        ].into_iter().collect();
+        let selected = select_lineage(lineages, "trt/todo").unwrap();
+        assert_eq!(selected.parent_event, "acq/1");
+    }
+
+    #[test]
+    fn select_most_recent_lineage_as_fallback() {
+        let lineages: HashMap<String, Lineage> = vec![
+            ("trt/todo".to_owned(), lineage("acq/1", 10.0)),
+            ("trt/other".to_owned(), lineage("acq/2", 20.0)),
+        ].into_iter().collect();
+        let selected = select_lineage(lineages, "global/events/0").unwrap();
+        assert_eq!(selected.parent_event, "acq/2");
+        assert!(select_lineage(HashMap::new(), "global/events").is_none());
+    }
+}
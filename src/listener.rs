@@ -0,0 +1,202 @@
+use {
+    crate::*,
+    serde::{Deserialize, Serialize},
+};
+
+/// version of the schema of the JSON listener messages, to
+/// be incremented on any incompatible change
+pub const LISTENER_MESSAGE_VERSION: u32 = 1;
+
+/// The format of the messages published on the listener channel
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ListenerFormat {
+    /// one line messages like "global/taken DONE some-event",
+    /// only for tasks being pushed or scheduled and events done
+    #[default]
+    Text,
+    /// JSON objects, covering all the steps of the handling of events
+    Json,
+}
+
+/// What happened
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "SCREAMING_SNAKE_CASE")]
+pub enum ListenerEvent {
+    /// an event was taken from an input queue
+    Taken,
+    /// a rule matched the event
+    RuleMatched,
+    /// a fetcher of a rule got its results
+    Fetched,
+    /// a task was pushed to its queue
+    Pushed,
+    /// a task was delayed
+    Scheduled,
+    /// a delayed task was pushed to its queue
+    Due,
+    /// a schedule pushed its event
+    Tick,
+    /// a task wasn't pushed as it was already in its set
+    DedupSkipped,
+    /// a task of dead workers was pushed back to its queue
+    Requeued,
+    /// a rule or the handling of an event failed
+    Failed,
+    /// the handling of an event is finished
+    Done,
+}
+
+/// A message published on the listener channel
+///
+/// Only the fields relevant to the kind of message are present
+/// in its JSON form.
+#[derive(Debug, Clone, Serialize)]
+pub struct ListenerMessage {
+    pub version: u32,
+    pub kind: ListenerEvent,
+    /// when it happened, in seconds since the Epoch, with
+    /// a sub-second precision
+    pub time: f64,
+    /// the taken queue of the watcher, or the key of the
+    /// schedule or of the scheduled set
+    pub source: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub event: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub rule: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub task: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub queue: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub set: Option<String>,
+    /// when a scheduled task is due
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub due: Option<f64>,
+    /// the URL queried by a fetcher
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub url: Option<String>,
+    /// the number of results of a fetcher
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub count: Option<usize>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+impl ListenerMessage {
+    pub fn new(kind: ListenerEvent, source: &str) -> Self {
+        Self {
+            version: LISTENER_MESSAGE_VERSION,
+            kind,
+            time: precise_now_secs(),
+            source: source.to_owned(),
+            event: None,
+            rule: None,
+            task: None,
+            queue: None,
+            set: None,
+            due: None,
+            url: None,
+            count: None,
+            error: None,
+        }
+    }
+
+    /// the message in the text format, if it's one of
+    /// the kinds published in this format
+    fn text(&self) -> Option<String> {
+        let event = self.event.as_deref().unwrap_or_default();
+        let task = self.task.as_deref().unwrap_or_default();
+        match self.kind {
+            ListenerEvent::Pushed => Some(format!("{} TRIGGER {} -> {}", self.source, event, task)),
+            ListenerEvent::Scheduled => Some(format!("{} SCHEDULE {} -> {}", self.source, event, task)),
+            ListenerEvent::Due => Some(format!("{} DUE {}", self.source, task)),
+            ListenerEvent::Tick => Some(format!("{} TICK -> {}", self.source, event)),
+            ListenerEvent::Done => Some(format!("{} DONE {}", self.source, event)),
+            _ => None,
+        }
+    }
+
+    /// the message as it must be published, if it must be
+    pub fn render(&self, format: ListenerFormat) -> Option<String> {
+        match format {
+            ListenerFormat::Text => self.text(),
+            ListenerFormat::Json => serde_json::to_string(self).ok(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use {
+        super::*,
+        serde_json::Value,
+        std::{sync::Arc, time::Duration},
+    };
+
+    #[test]
+    fn json_messages_are_versioned_and_sparse() {
+        let message = ListenerMessage {
+            event: Some("acq/a".to_owned()),
+            rule: Some("trt".to_owned()),
+            ..ListenerMessage::new(ListenerEvent::RuleMatched, "events/taken")
+        };
+        let json: Value = serde_json::from_str(&message.render(ListenerFormat::Json).unwrap()).unwrap();
+        let fields: Vec<&str> = json.as_object().unwrap().keys().map(|k| k.as_str()).collect();
+        assert_eq!(fields.len(), 6);
+        assert_eq!(json["version"], LISTENER_MESSAGE_VERSION);
+        assert_eq!(json["kind"], "RULE_MATCHED");
+        assert_eq!(json["source"], "events/taken");
+        assert_eq!(json["event"], "acq/a");
+        assert_eq!(json["rule"], "trt");
+        assert!(json["time"].as_f64().unwrap() > 0.0);
+        // this kind isn't published in the text format
+        assert_eq!(message.render(ListenerFormat::Text), None);
+    }
+
+    #[tokio::test]
+    async fn watcher_publishes_every_step_in_json() {
+        let rule = Rule::builder("trt", r"^acq/(?P<process>\w+)$")
+            .make(Maker::new("trt/${process}", "trt/todo").set("trt/set"))
+            .build()
+            .unwrap();
+        let conf = Conf::builder("redis://127.0.0.1/", "listener")
+            .listener_format(ListenerFormat::Json)
+            .watcher(WatcherConf::new("events").rule(rule))
+            .build()
+            .unwrap();
+        let memory = MemoryBackend::new();
+        let backend: Arc<dyn QueueBackend> = Arc::new(memory.clone());
+        let watcher = Arc::new(Watcher::new(&conf.watchers[0], &conf, &backend));
+        let handle = tokio::spawn(Arc::clone(&watcher).run());
+        memory.push("events", "acq/a").await.unwrap();
+        memory.push("events", "acq/a").await.unwrap();
+        for _ in 0..500 {
+            if memory.published("listener").len() >= 8 {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        watcher.control(WatcherControl::Stop);
+        handle.await.unwrap().unwrap();
+        let messages: Vec<Value> = memory.published("listener").iter()
+            .map(|message| serde_json::from_str(message).unwrap())
+            .collect();
+        let kinds: Vec<&str> = messages.iter()
+            .map(|message| message["kind"].as_str().unwrap())
+            .collect();
+        assert_eq!(
+            kinds,
+            vec![
+                "TAKEN", "RULE_MATCHED", "PUSHED", "DONE",
+                "TAKEN", "RULE_MATCHED", "DEDUP_SKIPPED", "DONE",
+            ],
+        );
+        assert_eq!(messages[0]["queue"], "events");
+        assert_eq!(messages[2]["task"], "trt/a");
+        assert_eq!(messages[2]["queue"], "trt/todo");
+        assert_eq!(messages[2]["set"], "trt/set");
+        assert!(messages.iter().all(|message| message["version"] == LISTENER_MESSAGE_VERSION));
+    }
+}
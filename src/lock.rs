@@ -0,0 +1,245 @@
+use {
+    crate::*,
+    serde::Deserialize,
+    std::{
+        sync::Arc,
+        time::{Duration, Instant},
+    },
+};
+
+/// Configuration of the locks giving the ownership of each
+/// watcher to only one of the resc instances running the
+/// same configuration
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+pub struct LockConf {
+    /// the prefix of the lock keys, followed by the input queue
+    #[serde(default = "LockConf::default_key_prefix")]
+    pub key_prefix: String,
+    /// how long a lock lasts when it's not renewed, that is how
+    /// long a standby instance waits before taking over the
+    /// watcher of a dead instance
+    #[serde(default = "LockConf::default_ttl", deserialize_with = "deserialize_duration")]
+    pub ttl: Duration,
+    /// the identifier of this instance, by default made of
+    /// the host name and the process id
+    #[serde(default = "LockConf::default_instance")]
+    pub instance: String,
+    /// whether the watchers are shared between the instances,
+    /// instead of being all run by one of them
+    #[serde(default)]
+    pub sharding: bool,
+    /// the sorted set of the live instances, when sharding
+    #[serde(default = "LockConf::default_members_key")]
+    pub members_key: String,
+}
+
+impl Default for LockConf {
+    fn default() -> Self {
+        Self {
+            key_prefix: LockConf::default_key_prefix(),
+            ttl: LockConf::default_ttl(),
+            instance: LockConf::default_instance(),
+            sharding: false,
+            members_key: LockConf::default_members_key(),
+        }
+    }
+}
+
+impl LockConf {
+    pub fn default_key_prefix() -> String {
+        "resc/locks".to_owned()
+    }
+    pub fn default_members_key() -> String {
+        "resc/members".to_owned()
+    }
+    pub fn default_ttl() -> Duration {
+        Duration::from_secs(10)
+    }
+    pub fn default_instance() -> String {
+        let host = std::env::var("HOSTNAME").ok()
+            .or_else(|| std::fs::read_to_string("/etc/hostname").ok())
+            .map(|host| host.trim().to_owned())
+            .filter(|host| !host.is_empty())
+            .unwrap_or_else(|| "resc".to_owned());
+        format!("{}/{}", host, std::process::id())
+    }
+}
+
+/// The lock of the input queue of a watcher, owned by
+/// one instance at most
+#[derive(Clone)]
+pub struct WatcherLock {
+    backend: Arc<dyn QueueBackend>,
+    key: String,
+    instance: String,
+    ttl: Duration,
+}
+
+impl WatcherLock {
+    pub fn new(lock_conf: &LockConf, input_queue: &str, backend: &Arc<dyn QueueBackend>) -> Self {
+        Self {
+            backend: Arc::clone(backend),
+            key: format!("{}/{}", &lock_conf.key_prefix, input_queue),
+            instance: lock_conf.instance.clone(),
+            ttl: lock_conf.ttl,
+        }
+    }
+    pub fn key(&self) -> &str {
+        &self.key
+    }
+    pub fn ttl(&self) -> Duration {
+        self.ttl
+    }
+    /// take the lock, or renew it if this instance already
+    /// owns it, returning whether this instance owns it
+    pub async fn acquire(&self) -> Result<bool, RescError> {
+        self.backend.lock(&self.key, &self.instance, self.ttl).await
+    }
+    /// release the lock, if this instance owns it
+    pub async fn release(&self) -> Result<(), RescError> {
+        self.backend.unlock(&self.key, &self.instance).await
+    }
+}
+
+/// The schedule of the renewals of a lock: it's renewed every
+/// third of its ttl and, after a failure, retried more often,
+/// until it's given up when it couldn't be renewed for half
+/// its ttl, measured from the start of the last successful
+/// renewal
+#[derive(Debug)]
+pub struct LockRenewal {
+    ttl: Duration,
+    /// when the last successful attempt started
+    renewed: Instant,
+    delay: Duration,
+}
+
+impl LockRenewal {
+    pub fn new(ttl: Duration, acquired: Instant) -> Self {
+        Self {
+            ttl,
+            renewed: acquired,
+            delay: ttl / 3,
+        }
+    }
+    /// the delay before the next attempt
+    pub fn delay(&self) -> Duration {
+        self.delay
+    }
+    /// the max duration of an attempt starting at `now`
+    pub fn remaining(&self, now: Instant) -> Duration {
+        (self.ttl / 2).saturating_sub(now.duration_since(self.renewed))
+    }
+    /// record the success of the attempt started at `attempt`
+    pub fn renewed(&mut self, attempt: Instant) {
+        self.renewed = attempt;
+        self.delay = self.ttl / 3;
+    }
+    /// record a failed attempt, returning whether
+    /// the lock must be given up
+    pub fn failed(&mut self, now: Instant) -> bool {
+        if now.duration_since(self.renewed) >= self.ttl / 2 {
+            return true;
+        }
+        self.delay = self.ttl / 12;
+        false
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TTL: Duration = Duration::from_secs(12);
+
+    #[test]
+    fn lock_is_renewed_every_third_of_its_ttl() {
+        let acquired = Instant::now();
+        let mut renewal = LockRenewal::new(TTL, acquired);
+        assert_eq!(renewal.delay(), Duration::from_secs(4));
+        let attempt = acquired + Duration::from_secs(4);
+        assert_eq!(renewal.remaining(attempt), Duration::from_secs(2));
+        renewal.renewed(attempt);
+        assert_eq!(renewal.delay(), Duration::from_secs(4));
+        assert_eq!(renewal.remaining(attempt), Duration::from_secs(6));
+    }
+
+    #[test]
+    fn lock_is_given_up_at_half_its_ttl() {
+        let acquired = Instant::now();
+        let mut renewal = LockRenewal::new(TTL, acquired);
+        // failures are retried every twelfth of the ttl
+        assert!(!renewal.failed(acquired + Duration::from_secs(4)));
+        assert_eq!(renewal.delay(), Duration::from_secs(1));
+        assert!(!renewal.failed(acquired + Duration::from_secs(5)));
+        // an attempt can't last past half the ttl
+        assert_eq!(renewal.remaining(acquired + Duration::from_secs(5)), Duration::from_secs(1));
+        assert!(renewal.failed(acquired + Duration::from_secs(6)));
+        // a success starts a new period
+        let mut renewal = LockRenewal::new(TTL, acquired);
+        assert!(!renewal.failed(acquired + Duration::from_secs(4)));
+        renewal.renewed(acquired + Duration::from_secs(5));
+        assert_eq!(renewal.delay(), Duration::from_secs(4));
+        assert!(!renewal.failed(acquired + Duration::from_secs(10)));
+        assert!(renewal.failed(acquired + Duration::from_secs(11)));
+    }
+
+    #[tokio::test]
+    async fn handlings_are_aborted_when_the_lock_is_lost() {
+        // a fetcher whose server never answers, so that the
+        // event is still being handled when the lock is lost
+        let server = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}/hanging", server.local_addr().unwrap());
+        let serving = tokio::spawn(async move {
+            let mut connections = Vec::new();
+            while let Ok((connection, _)) = server.accept().await {
+                connections.push(connection);
+            }
+        });
+        let rule = Rule::builder("trt", r"^acq/(?P<process>\w+)$")
+            .fetch(&url, "hanging")
+            .make(Maker::new("trt/${process}", "trt/todo"))
+            .build()
+            .unwrap();
+        let lock_conf = LockConf {
+            ttl: Duration::from_millis(300),
+            instance: "me".to_owned(),
+            ..LockConf::default()
+        };
+        let conf = Conf::builder("redis://127.0.0.1/", "listener")
+            .watcher(WatcherConf::new("events").rule(rule))
+            .locks(lock_conf)
+            .build()
+            .unwrap();
+        let memory = MemoryBackend::new();
+        let backend: Arc<dyn QueueBackend> = Arc::new(memory.clone());
+        let watcher = Arc::new(Watcher::new(&conf.watchers[0], &conf, &backend));
+        let handle = tokio::spawn(Arc::clone(&watcher).run());
+        memory.push("events", "acq/a").await.unwrap();
+        let wait_state = |state| {
+            let watcher = Arc::clone(&watcher);
+            async move {
+                for _ in 0..200 {
+                    if watcher.status().state == state {
+                        return;
+                    }
+                    tokio::time::sleep(Duration::from_millis(10)).await;
+                }
+                panic!("watcher not {:?}", state);
+            }
+        };
+        wait_state(WatcherState::Processing).await;
+        assert_eq!(watcher.status().current_events.len(), 1);
+        // another instance takes the lock
+        memory.delete("resc/locks/events").await.unwrap();
+        memory.lock("resc/locks/events", "other", Duration::from_secs(60)).await.unwrap();
+        wait_state(WatcherState::Standby).await;
+        assert!(watcher.status().current_events.is_empty());
+        // the event is left in the taken queue for the next owner
+        assert_eq!(memory.queue("events/taken"), vec!["acq/a"]);
+        assert!(memory.queue("trt/todo").is_empty());
+        watcher.control(WatcherControl::Stop);
+        handle.await.unwrap().unwrap();
+        serving.abort();
+    }
+}
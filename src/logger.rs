@@ -0,0 +1,23 @@
+use {
+    chrono::Local,
+    std::io::Write,
+};
+
+/// configure the logger of the resc binaries, the log
+/// level being given by the RUST_LOG env variable
+pub fn configure_logger() {
+    let env = env_logger::Env::default().filter_or(env_logger::DEFAULT_FILTER_ENV, "warn");
+    let mut builder = env_logger::Builder::from_env(env);
+    builder.default_format_module_path(false);
+    // log format with millisecond for better understanding of concurrency issues
+    builder.format(|buf, record| {
+        writeln!(
+            buf,
+            "{} [{}] - {}",
+            Local::now().format("%Y-%m-%dT%H:%M:%S%.3f"),
+            record.level(),
+            record.args()
+        )
+    });
+    builder.init();
+}
@@ -1,88 +1,144 @@
-//! Resc is a task orchestrator for distributed systems
-//! It's based on Rust and ensures in a safe way the
-//! generation of deduced tasks and their availability
-//! for external workers
+//! The resc command line: runs the orchestrator described by a
+//! configuration file, or inspects it
 //!
 //! Introduction and complete description in the [README](https://github.com/Canop/resc)
 
-mod conf;
-mod errors;
-mod fetcher;
-mod make;
-mod pattern;
-mod rule;
-mod ruleset;
-mod rule_result;
-mod serde_format;
-mod watcher;
-
 use {
-    chrono::Local,
     log::*,
-    std::{env, io::Write, thread},
+    resc::*,
+    std::{env, process::ExitCode, sync::Arc},
 };
 
-pub use {
-    conf::*,
-    errors::*,
-    fetcher::*,
-    make::*,
-    pattern::*,
-    rule::*,
-    ruleset::*,
-    rule_result::*,
-    serde_format::*,
-    watcher::*,
-};
+/// write the topology graph of the configuration on stdout
+///
+/// Usage: `resc graph [--dot|--mermaid] myconf.hjson`
+fn print_graph(args: &[String]) -> ExitCode {
+    let mut format = GraphFormat::Dot;
+    let mut config_filename = None;
+    for arg in args {
+        match arg.as_str() {
+            "--dot" => format = GraphFormat::Dot,
+            "--mermaid" => format = GraphFormat::Mermaid,
+            _ => config_filename = Some(arg),
+        }
+    }
+    let Some(config_filename) = config_filename else {
+        eprintln!("no configuration file provided");
+        return ExitCode::FAILURE;
+    };
+    let conf = match read_file(config_filename) {
+        Ok(conf) => conf,
+        Err(e) => {
+            eprintln!("{}", e);
+            return ExitCode::FAILURE;
+        }
+    };
+    let graph = Graph::new(&conf);
+    if let Err(e) = graph.write(&mut std::io::stdout(), format) {
+        eprintln!("{}", e);
+        return ExitCode::FAILURE;
+    }
+    ExitCode::SUCCESS
+}
 
-fn configure_logger() {
-    let env = env_logger::Env::default().filter_or(env_logger::DEFAULT_FILTER_ENV, "warn");
-    let mut builder = env_logger::Builder::from_env(env);
-    builder.default_format_module_path(false);
-    // log format with millisecond for better understanding of concurrency issues
-    builder.format(|buf, record| {
-        writeln!(
-            buf,
-            "{} [{}] - {}",
-            Local::now().format("%Y-%m-%dT%H:%M:%S%.3f"),
-            record.level(),
-            record.args()
-        )
-    });
-    builder.init();
+/// write the lineage of a task, up to its root event
+///
+/// `resc trace myconf.hjson some-task`
+async fn print_trace(args: &[String]) -> ExitCode {
+    let [config_filename, task] = args else {
+        eprintln!("usage: resc trace <configuration file> <task>");
+        return ExitCode::FAILURE;
+    };
+    let conf = match read_file(config_filename) {
+        Ok(conf) => conf,
+        Err(e) => {
+            eprintln!("{}", e);
+            return ExitCode::FAILURE;
+        }
+    };
+    let Some(lineage_conf) = &conf.lineage else {
+        eprintln!("lineage isn't recorded with this configuration");
+        return ExitCode::FAILURE;
+    };
+    let traced = async {
+        let mut con = RedisBackend::connect(&conf.redis).await?.con();
+        trace_task(&mut con, lineage_conf, task, &mut std::io::stdout()).await
+    };
+    if let Err(e) = traced.await {
+        eprintln!("{}", e);
+        return ExitCode::FAILURE;
+    }
+    ExitCode::SUCCESS
 }
 
-fn main() {
+#[tokio::main]
+async fn main() -> ExitCode {
     configure_logger();
 
-    info!("----- starting resc scheduler -----");
-
     let args: Vec<String> = env::args().collect();
     if args.len() < 2 {
-        panic!("no configuration file provided");
+        eprintln!("no configuration file provided");
+        return ExitCode::FAILURE;
+    }
+    if args[1] == "graph" {
+        return print_graph(&args[2..]);
     }
+    if args[1] == "trace" {
+        return print_trace(&args[2..]).await;
+    }
+
+    info!("----- starting resc scheduler -----");
+
     let config_filename = &args[1];
     info!("configuration read from {}", config_filename);
-    let conf = match conf::read_file(config_filename) {
+    let conf = match read_file(config_filename) {
         Ok(conf) => conf,
         Err(e) => {
             error!("Error reading configuration: {}", &e);
             eprintln!("{}", e);
-            return;
+            return ExitCode::FAILURE;
         }
     };
 
-    let mut handles = Vec::new();
-    for watcher_conf in &conf.watchers {
-        let mut watcher = Watcher::new(watcher_conf, &conf).unwrap();
-        handles.push(thread::spawn(move || {
-            watcher.run().unwrap();
-        }));
-    }
+    let runner = match Runner::start(Some(config_filename), &conf).await {
+        Ok(runner) => Arc::new(runner),
+        Err(e) => {
+            error!("Error starting: {}", &e);
+            eprintln!("{}", e);
+            return ExitCode::FAILURE;
+        }
+    };
 
-    debug!("all watchers started");
+    if let Some(metrics_conf) = conf.metrics.clone() {
+        tokio::spawn(async move {
+            if let Err(e) = serve_metrics(&metrics_conf).await {
+                error!("metrics listener stopped: {}", e);
+            }
+        });
+    }
+    if let Some(admin_conf) = conf.admin.clone() {
+        let runner = Arc::clone(&runner);
+        tokio::spawn(async move {
+            if let Err(e) = serve_admin(&admin_conf, runner).await {
+                error!("admin server stopped: {}", e);
+            }
+        });
+    }
 
-    for h in handles {
-        h.join().unwrap();
+    #[cfg(unix)]
+    tokio::spawn({
+        let runner = Arc::clone(&runner);
+        async move {
+            if let Err(e) = runner.reload_on_sighup().await {
+                error!("can't reload on SIGHUP: {}", e);
+            }
+        }
+    });
+    if conf.watch_conf_file {
+        tokio::spawn(Arc::clone(&runner).watch_conf_file());
     }
+
+    // everything runs in spawned tasks
+    std::future::pending::<()>().await;
+    ExitCode::SUCCESS
 }
@@ -1,11 +1,14 @@
 use {
     crate::*,
     serde::Deserialize,
-    std::collections::HashMap,
+    std::{
+        collections::HashMap,
+        time::Duration,
+    },
 };
 
 
-#[derive(Debug, Clone, Deserialize)]
+#[derive(Debug, Clone, PartialEq, Deserialize)]
 pub struct Maker {
 
     /// an optional name, for logs and for documentation in formats
@@ -23,18 +26,79 @@ pub struct Maker {
     /// the optional task set used for deduplicating
     pub set: Option<Pattern>,
 
+    /// an optional delay before the task is pushed to the queue,
+    /// for example "10m"
+    #[serde(default, deserialize_with = "deserialize_option_duration")]
+    pub delay: Option<Duration>,
+
+    /// an optional pattern giving the time at which the task must
+    /// be pushed to the queue, either in seconds since the Epoch
+    /// or as a RFC 3339 date. When there's also a delay, it's
+    /// added to this time
+    pub at: Option<Pattern>,
+
+    /// an optional retry policy, for a maker applied to failed
+    /// events: the task is made again, delayed by the backoff,
+    /// or pushed to the dead letter queue
+    pub retry: Option<Retry>,
+
 }
 impl Maker {
+    /// whether the tasks of this maker may be delayed, and
+    /// so go through the scheduled set
+    pub fn may_delay(&self) -> bool {
+        self.delay.is_some() || self.at.is_some() || self.retry.is_some()
+    }
+    /// compute the time at which the task is due, when it's
+    /// not to be pushed immediately
+    fn due(
+        &self,
+        props: &HashMap<String, String>,
+    ) -> Result<Option<f64>, RescError> {
+        let at = match &self.at {
+            Some(pattern) => Some(parse_time(&pattern.inject(props))?),
+            None => None,
+        };
+        Ok(match (at, self.delay) {
+            (Some(at), Some(delay)) => Some(at + delay.as_secs_f64()),
+            (Some(at), None) => Some(at),
+            (None, Some(delay)) => Some(now_secs() + delay.as_secs_f64()),
+            (None, None) => None,
+        })
+    }
     pub fn make(
         &self,
         props: &HashMap<String, String>,
         results: &mut Vec<RuleResult>,
-    ) {
+    ) -> Result<(), RescError> {
+        if let Some(retry) = &self.retry {
+            let attempt = retry.attempt(props)?;
+            let task = self.task.inject(props);
+            let queue = self.queue.inject(props);
+            if attempt >= retry.max_attempts {
+                results.push(RuleResult {
+                    task,
+                    queue: retry.dead_letter(&queue, props),
+                    set: None,
+                    due: None,
+                });
+            } else {
+                results.push(RuleResult {
+                    task,
+                    queue,
+                    set: self.set.as_ref().map(|pattern| pattern.inject(props)),
+                    due: Some(now_secs() + retry.delay(attempt).as_secs_f64()),
+                });
+            }
+            return Ok(());
+        }
         results.push(RuleResult {
             task: self.task.inject(props),
             queue: self.queue.inject(props),
             set: self.set.as_ref().map(|pattern| pattern.inject(props)),
+            due: self.due(props)?,
         });
+        Ok(())
     }
 }
 
@@ -42,7 +106,7 @@ impl Maker {
 /// elements can be given in an array or just single.
 /// For now there's no difference and a single works
 /// just as a 1 element array.
-#[derive(Debug, Clone, Deserialize)]
+#[derive(Debug, Clone, PartialEq, Deserialize)]
 #[serde(untagged)]
 pub enum Makers {
 
@@ -53,20 +117,27 @@ pub enum Makers {
 }
 
 impl Makers {
+    pub fn as_slice(&self) -> &[Maker] {
+        match self {
+            Self::Single(maker) => std::slice::from_ref(maker),
+            Self::Multiple(vec) => vec,
+        }
+    }
     pub fn make(
         &self,
         props: &HashMap<String, String>,
         results: &mut Vec<RuleResult>,
-    ) {
+    ) -> Result<(), RescError> {
         match self {
             Self::Single(maker) => {
-                maker.make(props, results);
+                maker.make(props, results)?;
             }
             Self::Multiple(vec) => {
                 for maker in vec {
-                    maker.make(props, results);
+                    maker.make(props, results)?;
                 }
             }
         }
+        Ok(())
     }
 }
@@ -0,0 +1,406 @@
+use {
+    crate::*,
+    async_trait::async_trait,
+    std::{
+        collections::{HashMap, HashSet, VecDeque},
+        sync::{Arc, Mutex},
+        time::{Duration, Instant},
+    },
+    tokio::sync::Notify,
+};
+
+#[derive(Default)]
+struct MemoryState {
+    /// the queues, with their head at the front
+    queues: HashMap<String, VecDeque<String>>,
+    sets: HashMap<String, HashMap<String, f64>>,
+    hashes: HashMap<String, HashMap<String, String>>,
+    /// the counters, which never expire
+    counters: HashMap<String, u64>,
+    /// the expiring keys, with their value and expiration
+    expiring: HashMap<String, (String, Instant)>,
+    /// the messages published on every channel
+    published: HashMap<String, Vec<String>>,
+    joins: HashMap<String, MemoryJoin>,
+}
+
+/// the arrivals in a join
+struct MemoryJoin {
+    arrived: HashSet<String>,
+    completer: Option<String>,
+    expiration: Instant,
+}
+
+/// A backend keeping everything in memory, in the process.
+///
+/// It's mostly useful for testing rules end to end, as it lets
+/// you push events and look at the resulting queues, sets and
+/// published messages. It's cheap to clone, the clones sharing
+/// the same state.
+#[derive(Clone, Default)]
+pub struct MemoryBackend {
+    state: Arc<Mutex<MemoryState>>,
+    /// notified on every push, to wake up the takers
+    pushed: Arc<Notify>,
+}
+
+impl MemoryBackend {
+    pub fn new() -> Self {
+        Self::default()
+    }
+    /// the content of a queue, head first
+    pub fn queue(&self, queue: &str) -> Vec<String> {
+        self.state.lock().unwrap().queues.get(queue)
+            .map(|values| values.iter().cloned().collect())
+            .unwrap_or_default()
+    }
+    /// the members of a set, with their scores
+    pub fn set(&self, set: &str) -> HashMap<String, f64> {
+        self.state.lock().unwrap().sets.get(set)
+            .cloned()
+            .unwrap_or_default()
+    }
+    /// the messages published on a channel, oldest first
+    pub fn published(&self, channel: &str) -> Vec<String> {
+        self.state.lock().unwrap().published.get(channel)
+            .cloned()
+            .unwrap_or_default()
+    }
+    fn move_tail_now(&self, from: &str, to: &str) -> Option<String> {
+        let mut state = self.state.lock().unwrap();
+        let value = state.queues.get_mut(from)?.pop_back()?;
+        state.queues.entry(to.to_owned()).or_default().push_front(value.clone());
+        Some(value)
+    }
+}
+
+struct MemoryTaker {
+    backend: MemoryBackend,
+}
+
+#[async_trait]
+impl EventTaker for MemoryTaker {
+    async fn take(
+        &mut self,
+        input_queue: &str,
+        taken_queue: &str,
+        timeout: f64,
+    ) -> Result<Option<String>, RescError> {
+        let deadline = tokio::time::Instant::now() + Duration::from_secs_f64(timeout);
+        loop {
+            // registering before checking, so that a push between
+            // the check and the wait isn't missed
+            let pushed = self.backend.pushed.notified();
+            if let Some(event) = self.backend.move_tail_now(input_queue, taken_queue) {
+                return Ok(Some(event));
+            }
+            if tokio::time::timeout_at(deadline, pushed).await.is_err() {
+                return Ok(None);
+            }
+        }
+    }
+}
+
+#[async_trait]
+impl QueueBackend for MemoryBackend {
+    async fn taker(&self) -> Result<Box<dyn EventTaker>, RescError> {
+        Ok(Box::new(MemoryTaker { backend: self.clone() }))
+    }
+    async fn push(&self, queue: &str, value: &str) -> Result<(), RescError> {
+        self.state.lock().unwrap().queues
+            .entry(queue.to_owned()).or_default()
+            .push_front(value.to_owned());
+        self.pushed.notify_waiters();
+        Ok(())
+    }
+    async fn push_deduplicated(
+        &self,
+        queue: &str,
+        set: &str,
+        value: &str,
+        score: f64,
+    ) -> Result<bool, RescError> {
+        {
+            let mut state = self.state.lock().unwrap();
+            let members = state.sets.entry(set.to_owned()).or_default();
+            if members.contains_key(value) {
+                return Ok(false);
+            }
+            members.insert(value.to_owned(), score);
+            state.queues.entry(queue.to_owned()).or_default().push_front(value.to_owned());
+        }
+        self.pushed.notify_waiters();
+        Ok(true)
+    }
+    async fn move_tail(&self, from: &str, to: &str) -> Result<Option<String>, RescError> {
+        let moved = self.move_tail_now(from, to);
+        if moved.is_some() {
+            self.pushed.notify_waiters();
+        }
+        Ok(moved)
+    }
+    async fn remove(&self, queue: &str, value: &str) -> Result<(), RescError> {
+        let mut state = self.state.lock().unwrap();
+        if let Some(values) = state.queues.get_mut(queue) {
+            if let Some(idx) = values.iter().position(|v| v == value) {
+                values.remove(idx);
+            }
+        }
+        Ok(())
+    }
+    async fn score(&self, set: &str, member: &str) -> Result<Option<f64>, RescError> {
+        Ok(self.state.lock().unwrap().sets.get(set)
+            .and_then(|members| members.get(member).copied()))
+    }
+    async fn add_to_set(&self, set: &str, member: &str, score: f64) -> Result<(), RescError> {
+        self.state.lock().unwrap().sets
+            .entry(set.to_owned()).or_default()
+            .insert(member.to_owned(), score);
+        Ok(())
+    }
+    async fn remove_from_set(&self, set: &str, member: &str) -> Result<bool, RescError> {
+        Ok(self.state.lock().unwrap().sets.get_mut(set)
+            .and_then(|members| members.remove(member))
+            .is_some())
+    }
+    async fn increment(&self, key: &str, _ttl: Duration) -> Result<u64, RescError> {
+        let mut state = self.state.lock().unwrap();
+        let counter = state.counters.entry(key.to_owned()).or_default();
+        *counter += 1;
+        Ok(*counter)
+    }
+    async fn delete(&self, key: &str) -> Result<(), RescError> {
+        let mut state = self.state.lock().unwrap();
+        state.queues.remove(key);
+        state.sets.remove(key);
+        state.hashes.remove(key);
+        state.counters.remove(key);
+        state.expiring.remove(key);
+        state.joins.remove(key);
+        Ok(())
+    }
+    async fn set_expiring(&self, key: &str, value: &str, ttl: Duration) -> Result<(), RescError> {
+        self.state.lock().unwrap().expiring
+            .insert(key.to_owned(), (value.to_owned(), Instant::now() + ttl));
+        Ok(())
+    }
+    async fn requeue_orphans(
+        &self,
+        taken_queue: &str,
+        queue: &str,
+        set: Option<&str>,
+        heartbeat: &str,
+        score: f64,
+    ) -> Result<Vec<String>, RescError> {
+        let mut state = self.state.lock().unwrap();
+        let alive = state.expiring.get(heartbeat)
+            .is_some_and(|(_, expiration)| *expiration > Instant::now());
+        if alive {
+            return Ok(Vec::new());
+        }
+        let MemoryState { queues, sets, .. } = &mut *state;
+        let Some(taken) = queues.get_mut(taken_queue) else {
+            return Ok(Vec::new());
+        };
+        let n = taken.len().min(MAX_REQUEUES_PER_CHECK);
+        let orphans: Vec<String> = (0..n).filter_map(|_| taken.pop_back()).collect();
+        let mut requeued = Vec::new();
+        for task in orphans {
+            if let Some(set) = set {
+                let members = sets.entry(set.to_owned()).or_default();
+                if members.contains_key(&task) {
+                    continue;
+                }
+                members.insert(task.clone(), score);
+            }
+            queues.entry(queue.to_owned()).or_default().push_front(task.clone());
+            requeued.push(task);
+        }
+        if !requeued.is_empty() {
+            self.pushed.notify_waiters();
+        }
+        Ok(requeued)
+    }
+    async fn lease(&self, queue: &str, leases: &str, deadline: f64) -> Result<Option<String>, RescError> {
+        let mut state = self.state.lock().unwrap();
+        let Some(task) = state.queues.get_mut(queue).and_then(|values| values.pop_back()) else {
+            return Ok(None);
+        };
+        state.sets.entry(leases.to_owned()).or_default().insert(task.clone(), deadline);
+        Ok(Some(task))
+    }
+    async fn extend_lease(&self, leases: &str, task: &str, deadline: f64) -> Result<bool, RescError> {
+        let mut state = self.state.lock().unwrap();
+        let Some(score) = state.sets.get_mut(leases).and_then(|members| members.get_mut(task)) else {
+            return Ok(false);
+        };
+        *score = deadline;
+        Ok(true)
+    }
+    async fn requeue_expired_leases(
+        &self,
+        leases: &str,
+        queue: &str,
+        set: Option<&str>,
+        now: f64,
+    ) -> Result<Vec<String>, RescError> {
+        let mut state = self.state.lock().unwrap();
+        let MemoryState { queues, sets, .. } = &mut *state;
+        let Some(members) = sets.get_mut(leases) else {
+            return Ok(Vec::new());
+        };
+        let mut expired: Vec<(String, f64)> = members.iter()
+            .filter(|(_, deadline)| **deadline <= now)
+            .map(|(task, deadline)| (task.clone(), *deadline))
+            .collect();
+        expired.sort_by(|a, b| a.1.total_cmp(&b.1));
+        expired.truncate(MAX_REQUEUES_PER_CHECK);
+        let mut requeued = Vec::new();
+        for (task, _) in expired {
+            sets.get_mut(leases).unwrap().remove(&task);
+            if let Some(set) = set {
+                let members = sets.entry(set.to_owned()).or_default();
+                if members.contains_key(&task) {
+                    continue;
+                }
+                members.insert(task.clone(), now);
+            }
+            queues.entry(queue.to_owned()).or_default().push_front(task.clone());
+            requeued.push(task);
+        }
+        if !requeued.is_empty() {
+            self.pushed.notify_waiters();
+        }
+        Ok(requeued)
+    }
+    async fn live_members(&self, set: &str, min_score: f64) -> Result<Vec<String>, RescError> {
+        let mut state = self.state.lock().unwrap();
+        let Some(members) = state.sets.get_mut(set) else {
+            return Ok(Vec::new());
+        };
+        members.retain(|_, score| *score >= min_score);
+        Ok(members.keys().cloned().collect())
+    }
+    async fn lock(&self, key: &str, owner: &str, ttl: Duration) -> Result<bool, RescError> {
+        let mut state = self.state.lock().unwrap();
+        let now = Instant::now();
+        let owned_by_other = state.expiring.get(key)
+            .is_some_and(|(value, expiration)| *expiration > now && value != owner);
+        if owned_by_other {
+            return Ok(false);
+        }
+        state.expiring.insert(key.to_owned(), (owner.to_owned(), now + ttl));
+        Ok(true)
+    }
+    async fn unlock(&self, key: &str, owner: &str) -> Result<(), RescError> {
+        let mut state = self.state.lock().unwrap();
+        if state.expiring.get(key).is_some_and(|(value, _)| value == owner) {
+            state.expiring.remove(key);
+        }
+        Ok(())
+    }
+    async fn arrive_in_join(
+        &self,
+        key: &str,
+        member: &str,
+        expected: &[String],
+        ttl: Duration,
+    ) -> Result<bool, RescError> {
+        let mut state = self.state.lock().unwrap();
+        let now = Instant::now();
+        if state.joins.get(key).is_some_and(|join| join.expiration <= now) {
+            state.joins.remove(key);
+        }
+        let join = state.joins.entry(key.to_owned()).or_insert_with(|| MemoryJoin {
+            arrived: HashSet::new(),
+            completer: None,
+            expiration: now,
+        });
+        join.arrived.insert(member.to_owned());
+        join.expiration = now + ttl;
+        if !expected.iter().all(|e| join.arrived.contains(e)) {
+            return Ok(false);
+        }
+        let completer = join.completer.get_or_insert_with(|| member.to_owned());
+        Ok(completer == member)
+    }
+    async fn debounce(
+        &self,
+        due: &str,
+        pending: &str,
+        key: &str,
+        event: &str,
+        due_time: f64,
+    ) -> Result<(), RescError> {
+        let mut state = self.state.lock().unwrap();
+        state.hashes.entry(pending.to_owned()).or_default()
+            .insert(key.to_owned(), event.to_owned());
+        state.sets.entry(due.to_owned()).or_default()
+            .entry(key.to_owned()).or_insert(due_time);
+        Ok(())
+    }
+    async fn close_windows(
+        &self,
+        due: &str,
+        pending: &str,
+        firing: &str,
+        now: f64,
+        max: usize,
+    ) -> Result<Vec<(String, String)>, RescError> {
+        let mut state = self.state.lock().unwrap();
+        let mut keys: Vec<(String, f64)> = state.sets.get(due)
+            .map(|members| {
+                members.iter()
+                    .filter(|(_, &score)| score <= now)
+                    .map(|(key, &score)| (key.clone(), score))
+                    .collect()
+            })
+            .unwrap_or_default();
+        keys.sort_by(|a, b| a.1.total_cmp(&b.1));
+        keys.truncate(max);
+        let mut closed = Vec::new();
+        for (key, _) in keys {
+            state.sets.get_mut(due).map(|members| members.remove(&key));
+            let event = state.hashes.get_mut(pending).and_then(|events| events.remove(&key));
+            if let Some(event) = event {
+                state.hashes.entry(firing.to_owned()).or_default()
+                    .insert(key.clone(), event.clone());
+                closed.push((key, event));
+            }
+        }
+        Ok(closed)
+    }
+    async fn hash_entries(&self, hash: &str) -> Result<Vec<(String, String)>, RescError> {
+        Ok(self.state.lock().unwrap().hashes.get(hash)
+            .map(|entries| entries.iter().map(|(k, v)| (k.clone(), v.clone())).collect())
+            .unwrap_or_default())
+    }
+    async fn remove_from_hash(&self, hash: &str, key: &str) -> Result<(), RescError> {
+        if let Some(entries) = self.state.lock().unwrap().hashes.get_mut(hash) {
+            entries.remove(key);
+        }
+        Ok(())
+    }
+    async fn release_join(&self, key: &str, member: &str) -> Result<(), RescError> {
+        let mut state = self.state.lock().unwrap();
+        if let Some(join) = state.joins.get_mut(key) {
+            if join.completer.as_deref() == Some(member) {
+                join.completer = None;
+            }
+        }
+        Ok(())
+    }
+    async fn complete_join(&self, key: &str, member: &str) -> Result<(), RescError> {
+        let mut state = self.state.lock().unwrap();
+        if state.joins.get(key).is_some_and(|join| join.completer.as_deref() == Some(member)) {
+            state.joins.remove(key);
+        }
+        Ok(())
+    }
+    async fn publish(&self, channel: &str, message: &str) -> Result<(), RescError> {
+        self.state.lock().unwrap().published
+            .entry(channel.to_owned()).or_default()
+            .push(message.to_owned());
+        Ok(())
+    }
+}
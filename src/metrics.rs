@@ -0,0 +1,275 @@
+use {
+    crate::*,
+    lazy_static::lazy_static,
+    serde::Deserialize,
+    std::{
+        collections::BTreeMap,
+        fmt::Write,
+        sync::Mutex,
+        time::Duration,
+    },
+};
+
+lazy_static! {
+    /// the metrics of the process, always counted but only
+    /// exposed when the metrics listener is configured
+    pub static ref METRICS: Metrics = Metrics::default();
+}
+
+/// Configuration of the HTTP listener exposing the metrics
+/// in the Prometheus text format
+#[derive(Debug, Clone, Deserialize)]
+pub struct MetricsConf {
+    #[serde(default = "MetricsConf::default_address")]
+    pub address: String,
+}
+
+impl MetricsConf {
+    pub fn default_address() -> String {
+        "127.0.0.1:9797".to_owned()
+    }
+}
+
+/// A counter with labels
+pub struct CounterVec {
+    name: &'static str,
+    help: &'static str,
+    label_names: &'static [&'static str],
+    values: Mutex<BTreeMap<Vec<String>, u64>>,
+}
+
+impl CounterVec {
+    fn new(
+        name: &'static str,
+        help: &'static str,
+        label_names: &'static [&'static str],
+    ) -> Self {
+        Self {
+            name,
+            help,
+            label_names,
+            values: Mutex::new(BTreeMap::new()),
+        }
+    }
+    pub fn inc(&self, labels: &[&str]) {
+        self.add(labels, 1);
+    }
+    pub fn add(&self, labels: &[&str], n: u64) {
+        let labels = labels.iter().map(|&l| l.to_owned()).collect();
+        *self.values.lock().unwrap().entry(labels).or_insert(0) += n;
+    }
+    fn render(&self, out: &mut String) {
+        writeln!(out, "# HELP {} {}", self.name, self.help).unwrap();
+        writeln!(out, "# TYPE {} counter", self.name).unwrap();
+        for (labels, value) in self.values.lock().unwrap().iter() {
+            writeln!(out, "{}{} {}", self.name, render_labels(self.label_names, labels, None), value).unwrap();
+        }
+    }
+}
+
+#[derive(Default)]
+struct HistogramValue {
+    bucket_counts: Vec<u64>,
+    sum: f64,
+    count: u64,
+}
+
+/// A histogram with labels
+pub struct HistogramVec {
+    name: &'static str,
+    help: &'static str,
+    label_names: &'static [&'static str],
+    buckets: &'static [f64],
+    values: Mutex<BTreeMap<Vec<String>, HistogramValue>>,
+}
+
+impl HistogramVec {
+    fn new(
+        name: &'static str,
+        help: &'static str,
+        label_names: &'static [&'static str],
+        buckets: &'static [f64],
+    ) -> Self {
+        Self {
+            name,
+            help,
+            label_names,
+            buckets,
+            values: Mutex::new(BTreeMap::new()),
+        }
+    }
+    pub fn observe(&self, labels: &[&str], duration: Duration) {
+        let secs = duration.as_secs_f64();
+        let labels = labels.iter().map(|&l| l.to_owned()).collect();
+        let mut values = self.values.lock().unwrap();
+        let value = values.entry(labels).or_default();
+        value.bucket_counts.resize(self.buckets.len(), 0);
+        for (i, &bound) in self.buckets.iter().enumerate() {
+            if secs <= bound {
+                value.bucket_counts[i] += 1;
+            }
+        }
+        value.sum += secs;
+        value.count += 1;
+    }
+    fn render(&self, out: &mut String) {
+        writeln!(out, "# HELP {} {}", self.name, self.help).unwrap();
+        writeln!(out, "# TYPE {} histogram", self.name).unwrap();
+        for (labels, value) in self.values.lock().unwrap().iter() {
+            for (bound, count) in self.buckets.iter().zip(&value.bucket_counts) {
+                let le = bound.to_string();
+                writeln!(
+                    out, "{}_bucket{} {}",
+                    self.name, render_labels(self.label_names, labels, Some(&le)), count,
+                ).unwrap();
+            }
+            writeln!(
+                out, "{}_bucket{} {}",
+                self.name, render_labels(self.label_names, labels, Some("+Inf")), value.count,
+            ).unwrap();
+            let labels = render_labels(self.label_names, labels, None);
+            writeln!(out, "{}_sum{} {}", self.name, &labels, value.sum).unwrap();
+            writeln!(out, "{}_count{} {}", self.name, &labels, value.count).unwrap();
+        }
+    }
+}
+
+fn render_labels(names: &[&str], values: &[String], le: Option<&str>) -> String {
+    let mut pairs: Vec<String> = names.iter().zip(values)
+        .map(|(name, value)| {
+            let value = value.replace('\\', "\\\\").replace('"', "\\\"").replace('\n', "\\n");
+            format!("{}=\"{}\"", name, value)
+        })
+        .collect();
+    if let Some(le) = le {
+        pairs.push(format!("le=\"{}\"", le));
+    }
+    if pairs.is_empty() {
+        String::new()
+    } else {
+        format!("{{{}}}", pairs.join(","))
+    }
+}
+
+/// All the metrics resc maintains
+pub struct Metrics {
+    pub events_taken: CounterVec,
+    pub rule_matches: CounterVec,
+    pub tasks_pushed: CounterVec,
+    pub dedup_skips: CounterVec,
+    pub fetch_duration: HistogramVec,
+    pub fetch_errors: CounterVec,
+    pub taken_queue_recoveries: CounterVec,
+    pub redis_reconnects: CounterVec,
+    pub tasks_requeued: CounterVec,
+}
+
+impl Default for Metrics {
+    fn default() -> Self {
+        Self {
+            events_taken: CounterVec::new(
+                "resc_events_taken_total",
+                "Events taken from the input queue of a watcher",
+                &["watcher"],
+            ),
+            rule_matches: CounterVec::new(
+                "resc_rule_matches_total",
+                "Events matched by a rule",
+                &["rule"],
+            ),
+            tasks_pushed: CounterVec::new(
+                "resc_tasks_pushed_total",
+                "Tasks pushed to a queue",
+                &["queue"],
+            ),
+            dedup_skips: CounterVec::new(
+                "resc_dedup_skips_total",
+                "Tasks not pushed to a queue because already in its task set",
+                &["queue"],
+            ),
+            fetch_duration: HistogramVec::new(
+                "resc_fetch_duration_seconds",
+                "Duration of the queries of a fetcher",
+                &["fetcher"],
+                &[0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0],
+            ),
+            fetch_errors: CounterVec::new(
+                "resc_fetch_errors_total",
+                "Failed queries of a fetcher",
+                &["fetcher"],
+            ),
+            taken_queue_recoveries: CounterVec::new(
+                "resc_taken_queue_recoveries_total",
+                "Events moved back from the taken queue to the input queue of a watcher",
+                &["watcher"],
+            ),
+            redis_reconnects: CounterVec::new(
+                "resc_redis_reconnects_total",
+                "Reconnections of the event taker of a watcher, and restarts of the watcher, after a connection loss",
+                &["watcher"],
+            ),
+            tasks_requeued: CounterVec::new(
+                "resc_tasks_requeued_total",
+                "Tasks of dead workers moved back from their taken queue to their queue",
+                &["taken_queue"],
+            ),
+        }
+    }
+}
+
+impl Metrics {
+    /// render all metrics in the Prometheus text format
+    pub fn render(&self) -> String {
+        let mut out = String::new();
+        self.events_taken.render(&mut out);
+        self.rule_matches.render(&mut out);
+        self.tasks_pushed.render(&mut out);
+        self.dedup_skips.render(&mut out);
+        self.fetch_duration.render(&mut out);
+        self.fetch_errors.render(&mut out);
+        self.taken_queue_recoveries.render(&mut out);
+        self.redis_reconnects.render(&mut out);
+        self.tasks_requeued.render(&mut out);
+        out
+    }
+}
+
+/// serve the metrics, forever
+pub async fn serve_metrics(metrics_conf: &MetricsConf) -> Result<(), RescError> {
+    http::serve(&metrics_conf.address, |request: HttpRequest| async move {
+        match (request.method.as_str(), request.path.as_str()) {
+            ("GET", "/metrics") => HttpResponse::ok(
+                "text/plain; version=0.0.4",
+                METRICS.render(),
+            ),
+            _ => HttpResponse::not_found(),
+        }
+    }).await?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn metrics_are_rendered_in_prometheus_format() {
+        let metrics = Metrics::default();
+        metrics.tasks_pushed.add(&["trt/todo"], 2);
+        metrics.tasks_pushed.inc(&["say \"hi\""]);
+        metrics.fetch_duration.observe(&["http://ws/${p}"], Duration::from_millis(30));
+        let rendered = metrics.render();
+        let lines: Vec<&str> = rendered.lines().collect();
+        assert!(lines.contains(&"# TYPE resc_tasks_pushed_total counter"));
+        assert!(lines.contains(&"resc_tasks_pushed_total{queue=\"trt/todo\"} 2"));
+        assert!(lines.contains(&"resc_tasks_pushed_total{queue=\"say \\\"hi\\\"\"} 1"));
+        assert!(lines.contains(&"# TYPE resc_fetch_duration_seconds histogram"));
+        assert!(lines.contains(&"resc_fetch_duration_seconds_bucket{fetcher=\"http://ws/${p}\",le=\"0.025\"} 0"));
+        assert!(lines.contains(&"resc_fetch_duration_seconds_bucket{fetcher=\"http://ws/${p}\",le=\"0.05\"} 1"));
+        assert!(lines.contains(&"resc_fetch_duration_seconds_bucket{fetcher=\"http://ws/${p}\",le=\"+Inf\"} 1"));
+        assert!(lines.contains(&"resc_fetch_duration_seconds_count{fetcher=\"http://ws/${p}\"} 1"));
+        // counters without values are still described
+        assert!(lines.contains(&"# TYPE resc_dedup_skips_total counter"));
+        assert!(!rendered.contains("resc_dedup_skips_total{"));
+    }
+}
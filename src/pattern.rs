@@ -8,16 +8,23 @@ use {
 /// Patterns are built from strings like "bla ${some_var} ${some.otherone} bla"
 /// and are expanded with HashMap<String, String>
 /// TODO use an enum, and define an identity for the simple case
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, PartialEq)]
 pub struct Pattern {
     pub src: String,
 }
 
+impl From<&str> for Pattern {
+    fn from(src: &str) -> Self {
+        Self { src: src.to_owned() }
+    }
+}
+
+lazy_static! {
+    static ref OUT_GROUP_REGEX: Regex = Regex::new(r"\$\{([\w.]+)\}").unwrap();
+}
+
 impl Pattern {
     pub fn inject(&self, props: &HashMap<String, String>) -> String {
-        lazy_static! {
-            static ref OUT_GROUP_REGEX: Regex = Regex::new(r"\$\{([\w.]+)\}").unwrap();
-        }
         OUT_GROUP_REGEX
             .replace_all(&self.src, |caps: &Captures| {
                 match props.get(caps.get(1).unwrap().as_str()) {
@@ -27,6 +34,79 @@ impl Pattern {
             })
             .to_string()
     }
+    /// the names of the variables used in the pattern
+    pub fn variables(&self) -> Vec<&str> {
+        OUT_GROUP_REGEX
+            .captures_iter(&self.src)
+            .map(|caps| caps.get(1).unwrap().as_str())
+            .collect()
+    }
+    /// build the regex matching the strings this pattern produces,
+    /// with a named group per variable (only the first occurrence
+    /// of a variable is captured)
+    pub fn to_regex(&self) -> Result<Regex, regex::Error> {
+        let mut src = String::from("^");
+        let mut last = 0;
+        let mut seen = Vec::new();
+        for caps in OUT_GROUP_REGEX.captures_iter(&self.src) {
+            let whole = caps.get(0).unwrap();
+            src.push_str(&regex::escape(&self.src[last..whole.start()]));
+            if seen.contains(&&caps[1]) {
+                src.push_str("(?:.+?)");
+            } else {
+                src.push_str(&format!("(?P<{}>.+?)", &caps[1]));
+                seen.push(caps.get(1).unwrap().as_str());
+            }
+            last = whole.end();
+        }
+        src.push_str(&regex::escape(&self.src[last..]));
+        src.push('$');
+        Regex::new(&src)
+    }
+    /// the pattern as a sequence of literal chars and of
+    /// variables, each variable matching at least one char
+    fn tokens(&self) -> Vec<Option<char>> {
+        let mut tokens = Vec::new();
+        let mut last = 0;
+        for whole in OUT_GROUP_REGEX.find_iter(&self.src) {
+            tokens.extend(self.src[last..whole.start()].chars().map(Some));
+            tokens.push(None);
+            last = whole.end();
+        }
+        tokens.extend(self.src[last..].chars().map(Some));
+        tokens
+    }
+    /// whether some string may be produced by both patterns
+    pub fn overlaps(&self, other: &Pattern) -> bool {
+        let (a, b) = (self.tokens(), other.tokens());
+        // A state is a position in both patterns. A variable can consume a
+        // char and stay at the same position, or consume one and move on.
+        let mut seen = vec![vec![false; b.len() + 1]; a.len() + 1];
+        let mut stack = vec![(0, 0)];
+        while let Some((i, j)) = stack.pop() {
+            if seen[i][j] {
+                continue;
+            }
+            seen[i][j] = true;
+            if i == a.len() && j == b.len() {
+                return true;
+            }
+            let (Some(&ta), Some(&tb)) = (a.get(i), b.get(j)) else {
+                continue;
+            };
+            if ta.is_some() && tb.is_some() && ta != tb {
+                continue;
+            }
+            stack.push((i + 1, j + 1));
+            if ta.is_none() {
+                stack.push((i, j + 1));
+            }
+            if tb.is_none() {
+                stack.push((i + 1, j));
+            }
+        }
+        false
+    }
     /// produce the pattern to use when the config gives none
     pub fn default_task() -> Self {
         Self { src: "${input_task}".to_owned() }
@@ -41,3 +121,39 @@ impl<'de> Deserialize<'de> for Pattern {
         Ok(Self { src })
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn inject() {
+        let props: HashMap<String, String> = vec![
+            ("process".to_owned(), "a".to_owned()),
+            ("product".to_owned(), "1".to_owned()),
+        ].into_iter().collect();
+        assert_eq!(Pattern::from("trt/${process}/${product}").inject(&props), "trt/a/1");
+        assert_eq!(Pattern::from("trt/${nope}").inject(&props), "trt/-missing group!-");
+    }
+
+    #[test]
+    fn to_regex() {
+        let regex = Pattern::from("trt/${process}/${product}/${process}").to_regex().unwrap();
+        let caps = regex.captures("trt/a/1/a").unwrap();
+        assert_eq!(&caps["process"], "a");
+        assert_eq!(&caps["product"], "1");
+        assert!(!regex.is_match("trt/a/1"));
+    }
+
+    #[test]
+    fn overlaps() {
+        let overlap = |a: &str, b: &str| Pattern::from(a).overlaps(&Pattern::from(b));
+        assert!(!overlap("acq/${p}/${q}", "trt/${p}/${q}"));
+        assert!(overlap("acq/${p}/${q}", "acq/${p}"));
+        assert!(overlap("${p}/x", "a/${q}"));
+        assert!(overlap("a${p}b", "axb"));
+        assert!(!overlap("a${p}b", "ab"));
+        assert!(!overlap("propagate/${p}", "publish/${p}"));
+        assert!(overlap("same", "same"));
+    }
+}
@@ -0,0 +1,207 @@
+use {
+    crate::*,
+    log::*,
+    serde::Deserialize,
+    std::{
+        sync::Arc,
+        time::Duration,
+    },
+};
+
+/// time between two checks of the heartbeats
+const REAPER_PERIOD: Duration = Duration::from_secs(5);
+
+/// max number of tasks requeued from a taken queue or a lease set in one check
+pub const MAX_REQUEUES_PER_CHECK: usize = 1000;
+
+/// the key whose presence tells the workers of a taken queue are alive
+pub fn heartbeat_key(taken_queue: &str) -> String {
+    format!("{}/heartbeat", taken_queue)
+}
+
+/// The taken queue of some workers, whose tasks must be
+/// requeued when the workers stop beating, or their lease
+/// set, whose tasks must be requeued when their lease expires
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+pub struct ReaperConf {
+    /// the taken queue of the workers, or their lease set
+    pub taken_queue: String,
+    /// whether the workers lease their tasks, the taken
+    /// queue being then a lease set
+    #[serde(default)]
+    pub lease: bool,
+    /// the queue where the orphan tasks are pushed back
+    pub queue: String,
+    /// the task set of this queue, if it's deduplicated
+    pub set: Option<String>,
+    /// the heartbeat key of the workers, by default the
+    /// taken queue followed by "/heartbeat"
+    pub heartbeat: Option<String>,
+}
+
+impl ReaperConf {
+    pub fn new(taken_queue: &str, queue: &str) -> Self {
+        Self {
+            taken_queue: taken_queue.to_owned(),
+            lease: false,
+            queue: queue.to_owned(),
+            set: None,
+            heartbeat: None,
+        }
+    }
+    pub fn heartbeat(&self) -> String {
+        match &self.heartbeat {
+            Some(key) => key.clone(),
+            None => heartbeat_key(&self.taken_queue),
+        }
+    }
+    /// check the keys moved between by the reaper are in
+    /// the same slot of a Redis Cluster
+    pub fn check_cluster_slots(&self) -> Result<(), ConfError> {
+        let heartbeat = self.heartbeat();
+        let mut keys = vec![self.taken_queue.as_str(), self.queue.as_str()];
+        if !self.lease {
+            keys.push(&heartbeat);
+        }
+        if let Some(set) = &self.set {
+            keys.push(set);
+        }
+        check_same_slot(&keys)
+    }
+}
+
+/// A reaper watches the heartbeat of the workers of a taken queue
+/// and moves the tasks of this taken queue back to their task queue
+/// when the workers seem dead.
+///
+/// The check of the heartbeat and the move are atomic, so a
+/// worker starting again won't see its new tasks requeued.
+///
+/// In lease mode, the reaper moves back the tasks whose
+/// lease expired.
+#[derive(Clone)]
+pub struct Reaper {
+    backend: Arc<dyn QueueBackend>,
+    dispatcher: Dispatcher,
+    conf: ReaperConf,
+    heartbeat: String,
+}
+
+impl Reaper {
+
+    pub fn new(
+        reaper_conf: &ReaperConf,
+        global_conf: &Conf,
+        backend: &Arc<dyn QueueBackend>,
+    ) -> Self {
+        Self::with_dispatcher(
+            reaper_conf,
+            Dispatcher::new(global_conf, Arc::clone(backend)),
+            backend,
+        )
+    }
+
+    /// make a reaper sending its notifications with
+    /// the given dispatcher
+    pub fn with_dispatcher(
+        reaper_conf: &ReaperConf,
+        dispatcher: Dispatcher,
+        backend: &Arc<dyn QueueBackend>,
+    ) -> Self {
+        Self {
+            backend: Arc::clone(backend),
+            dispatcher,
+            conf: reaper_conf.clone(),
+            heartbeat: reaper_conf.heartbeat(),
+        }
+    }
+
+    pub async fn run(&mut self) {
+        info!("reaper launched on taken queue {:?}...", &self.conf.taken_queue);
+        loop {
+            if let Err(e) = self.requeue_orphans().await {
+                error!("reaping {:?} failed : {}", &self.conf.taken_queue, e);
+            }
+            tokio::time::sleep(REAPER_PERIOD).await;
+        }
+    }
+
+    /// requeue the tasks of the taken queue, if its workers
+    /// stopped beating, returning how many were requeued
+    pub async fn requeue_orphans(&mut self) -> Result<usize, RescError> {
+        let requeued = if self.conf.lease {
+            self.backend.requeue_expired_leases(
+                &self.conf.taken_queue,
+                &self.conf.queue,
+                self.conf.set.as_deref(),
+                precise_now_secs(),
+            ).await?
+        } else {
+            self.backend.requeue_orphans(
+                &self.conf.taken_queue,
+                &self.conf.queue,
+                self.conf.set.as_deref(),
+                &self.heartbeat,
+                now_secs(),
+            ).await?
+        };
+        for task in &requeued {
+            warn!(
+                "  ->  {:?} orphan in {:?}, pushed back to {:?}",
+                task, &self.conf.taken_queue, &self.conf.queue,
+            );
+            METRICS.tasks_requeued.inc(&[&self.conf.taken_queue]);
+            self.dispatcher.notify(ListenerMessage {
+                task: Some(task.clone()),
+                queue: Some(self.conf.queue.clone()),
+                set: self.conf.set.clone(),
+                ..ListenerMessage::new(ListenerEvent::Requeued, &self.conf.taken_queue)
+            }).await?;
+        }
+        Ok(requeued.len())
+    }
+
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn reaper(memory: &MemoryBackend) -> Reaper {
+        let reaper_conf = ReaperConf {
+            set: Some("trt/set".to_owned()),
+            ..ReaperConf::new("trt/taken", "trt/todo")
+        };
+        let conf = Conf::builder("redis://127.0.0.1/", "listener")
+            .reaper(reaper_conf.clone())
+            .build()
+            .unwrap();
+        let backend: Arc<dyn QueueBackend> = Arc::new(memory.clone());
+        Reaper::new(&reaper_conf, &conf, &backend)
+    }
+
+    #[tokio::test]
+    async fn orphaned_taken_queue_is_requeued() {
+        let memory = MemoryBackend::new();
+        let mut reaper = reaper(&memory);
+        for task in ["trt/a", "trt/b", "trt/c"] {
+            memory.push("trt/taken", task).await.unwrap();
+        }
+        // trt/c was queued again while being done
+        memory.add_to_set("trt/set", "trt/c", 1.0).await.unwrap();
+        let heartbeat = heartbeat_key("trt/taken");
+        memory.set_expiring(&heartbeat, "worker", Duration::from_millis(100)).await.unwrap();
+        // the workers are alive
+        assert_eq!(reaper.requeue_orphans().await.unwrap(), 0);
+        assert_eq!(memory.queue("trt/taken").len(), 3);
+        tokio::time::sleep(Duration::from_millis(150)).await;
+        // the heartbeat expired
+        assert_eq!(reaper.requeue_orphans().await.unwrap(), 2);
+        assert!(memory.queue("trt/taken").is_empty());
+        assert_eq!(memory.queue("trt/todo"), vec!["trt/b", "trt/a"]);
+        let set = memory.set("trt/set");
+        assert_eq!(set.len(), 3);
+        assert_eq!(set["trt/c"], 1.0);
+        assert_eq!(reaper.requeue_orphans().await.unwrap(), 0);
+    }
+}
@@ -0,0 +1,566 @@
+use {
+    crate::*,
+    async_trait::async_trait,
+    lazy_static::lazy_static,
+    redis::{
+        aio::{ConnectionLike, ConnectionManager},
+        cluster::ClusterClient,
+        sentinel::{SentinelClientBuilder, SentinelServerType},
+        AsyncCommands,
+        ConnectionAddr,
+        IntoConnectionInfo,
+        Script,
+        TlsCertificates,
+        TlsMode,
+    },
+    std::{
+        collections::HashMap,
+        time::Duration,
+    },
+};
+
+lazy_static! {
+    /// If the heartbeat key (KEYS[2]) doesn't exist, move the tasks
+    /// of the taken queue (KEYS[1]) to the task queue (KEYS[3]),
+    /// unless they're in the optional task set (KEYS[4]).
+    /// Returns the requeued tasks.
+    static ref REQUEUE_ORPHANS: Script = Script::new(r"
+        if redis.call('EXISTS', KEYS[2]) == 1 then
+            return {}
+        end
+        local requeued = {}
+        for i = 1, tonumber(ARGV[2]) do
+            local task = redis.call('RPOP', KEYS[1])
+            if not task then
+                break
+            end
+            if not KEYS[4] or not redis.call('ZSCORE', KEYS[4], task) then
+                if KEYS[4] then
+                    redis.call('ZADD', KEYS[4], ARGV[1], task)
+                end
+                redis.call('LPUSH', KEYS[3], task)
+                table.insert(requeued, task)
+            end
+        end
+        return requeued
+    ");
+
+    /// Add the task (ARGV[1]) to the task set (KEYS[2]) with the
+    /// score ARGV[2] and push it to the queue (KEYS[1]), unless it's
+    /// already in the set. Returns 1 when the task was pushed
+    static ref PUSH_DEDUPLICATED: Script = Script::new(r"
+        if redis.call('ZADD', KEYS[2], 'NX', ARGV[2], ARGV[1]) == 1 then
+            redis.call('LPUSH', KEYS[1], ARGV[1])
+            return 1
+        end
+        return 0
+    ");
+
+    /// Move the tail of the queue (KEYS[1]) to the lease set (KEYS[2])
+    /// with the deadline as score. Returns the leased task, if any.
+    static ref LEASE: Script = Script::new(r"
+        local task = redis.call('RPOP', KEYS[1])
+        if task then
+            redis.call('ZADD', KEYS[2], ARGV[1], task)
+        end
+        return task
+    ");
+
+    /// Change the deadline of a lease, if it still exists
+    static ref EXTEND_LEASE: Script = Script::new(r"
+        if redis.call('ZSCORE', KEYS[1], ARGV[2]) then
+            redis.call('ZADD', KEYS[1], ARGV[1], ARGV[2])
+            return 1
+        end
+        return 0
+    ");
+
+    /// Move the tasks of the lease set (KEYS[1]) whose deadline is
+    /// past to the task queue (KEYS[2]), unless they're in the
+    /// optional task set (KEYS[3]). Returns the requeued tasks.
+    static ref REQUEUE_EXPIRED_LEASES: Script = Script::new(r"
+        local expired = redis.call('ZRANGEBYSCORE', KEYS[1], '-inf', ARGV[1], 'LIMIT', 0, ARGV[2])
+        local requeued = {}
+        for _, task in ipairs(expired) do
+            redis.call('ZREM', KEYS[1], task)
+            if not KEYS[3] or not redis.call('ZSCORE', KEYS[3], task) then
+                if KEYS[3] then
+                    redis.call('ZADD', KEYS[3], ARGV[1], task)
+                end
+                redis.call('LPUSH', KEYS[2], task)
+                table.insert(requeued, task)
+            end
+        end
+        return requeued
+    ");
+
+    /// Take or renew the lock (KEYS[1]) for the owner (ARGV[1]),
+    /// for ARGV[2] milliseconds. Returns 1 when the owner has the lock
+    static ref LOCK: Script = Script::new(r"
+        local owner = redis.call('GET', KEYS[1])
+        if owner and owner ~= ARGV[1] then
+            return 0
+        end
+        redis.call('SET', KEYS[1], ARGV[1], 'PX', ARGV[2])
+        return 1
+    ");
+
+    /// Delete the lock (KEYS[1]) if it's owned by ARGV[1]
+    static ref UNLOCK: Script = Script::new(r"
+        if redis.call('GET', KEYS[1]) == ARGV[1] then
+            redis.call('DEL', KEYS[1])
+        end
+        return 0
+    ");
+
+    /// Record the arrival of a member (ARGV[1]) in the join hash (KEYS[1]),
+    /// at time ARGV[2], refreshing its expiry (ARGV[3], in ms), and check
+    /// whether all the expected members (ARGV[5..]) arrived. The first
+    /// member completing the join is recorded in the ARGV[4] field,
+    /// and 1 is returned for it only.
+    static ref ARRIVE_IN_JOIN: Script = Script::new(r"
+        redis.call('HSET', KEYS[1], ARGV[1], ARGV[2])
+        redis.call('PEXPIRE', KEYS[1], ARGV[3])
+        for i = 5, #ARGV do
+            if redis.call('HEXISTS', KEYS[1], ARGV[i]) == 0 then
+                return 0
+            end
+        end
+        redis.call('HSETNX', KEYS[1], ARGV[4], ARGV[1])
+        if redis.call('HGET', KEYS[1], ARGV[4]) == ARGV[1] then
+            return 1
+        end
+        return 0
+    ");
+
+    /// Atomically move the events whose window is closed from the
+    /// pending hash (KEYS[2], with the due times in KEYS[1]) to the
+    /// firing hash (KEYS[3]). Returns the moved keys and events.
+    static ref CLOSE_WINDOWS: Script = Script::new(r"
+        local keys = redis.call('ZRANGEBYSCORE', KEYS[1], '-inf', ARGV[1], 'LIMIT', 0, ARGV[2])
+        local closed = {}
+        for _, key in ipairs(keys) do
+            redis.call('ZREM', KEYS[1], key)
+            local event = redis.call('HGET', KEYS[2], key)
+            if event then
+                redis.call('HDEL', KEYS[2], key)
+                redis.call('HSET', KEYS[3], key, event)
+                table.insert(closed, key)
+                table.insert(closed, event)
+            end
+        end
+        return closed
+    ");
+
+    /// Remove the completer field (ARGV[1]) of the join hash (KEYS[1])
+    /// if it's the member ARGV[2]
+    static ref RELEASE_JOIN: Script = Script::new(r"
+        if redis.call('HGET', KEYS[1], ARGV[1]) == ARGV[2] then
+            redis.call('HDEL', KEYS[1], ARGV[1])
+        end
+        return 0
+    ");
+
+    /// Delete the join hash (KEYS[1]) if its completer field (ARGV[1])
+    /// is the member ARGV[2]
+    static ref COMPLETE_JOIN: Script = Script::new(r"
+        if redis.call('HGET', KEYS[1], ARGV[1]) == ARGV[2] then
+            redis.call('DEL', KEYS[1])
+        end
+        return 0
+    ");
+}
+
+/// the field of a join hash holding the member which completed it
+const JOIN_COMPLETER_FIELD: &str = "resc/completer";
+
+/// The Redis backend, where queues are lists, sets are
+/// sorted sets and channels are pub/sub channels
+#[derive(Clone)]
+pub struct RedisBackend {
+    connector: RedisConnector,
+    con: RedisConnection, // shared with all watchers
+}
+
+/// What's needed to make the dedicated connections of the takers
+#[derive(Clone)]
+enum RedisConnector {
+    Single(redis::Client),
+    Sentinel(SentinelConnection),
+    Cluster(ClusterClient),
+}
+
+impl RedisBackend {
+    /// connect to a single server
+    pub async fn new(client: redis::Client) -> Result<Self, RescError> {
+        let con = ConnectionManager::new(client.clone()).await?;
+        Ok(Self {
+            connector: RedisConnector::Single(client),
+            con: RedisConnection::Single(con),
+        })
+    }
+    /// connect to the server, sentinels or cluster of the configuration
+    pub async fn connect(conf: &RedisConf) -> Result<Self, RescError> {
+        conf.validate()?;
+        let (username, password) = conf.credentials()?;
+        let certificates = conf.tls.as_ref().map(TlsConf::certificates).transpose()?;
+        if let Some(sentinel_conf) = &conf.sentinel {
+            let con = Self::connect_sentinel(sentinel_conf, username, password, certificates).await?;
+            return Ok(Self {
+                connector: RedisConnector::Sentinel(con.clone()),
+                con: RedisConnection::Sentinel(con),
+            });
+        }
+        if let Some(cluster_conf) = &conf.cluster {
+            let mut builder = ClusterClient::builder(cluster_conf.nodes.clone());
+            if let Some(username) = username {
+                builder = builder.username(username);
+            }
+            if let Some(password) = password {
+                builder = builder.password(password);
+            }
+            if let Some(certificates) = certificates {
+                builder = builder.certs(certificates);
+            }
+            let client = builder.build()?;
+            let con = client.get_async_connection().await?;
+            return Ok(Self {
+                connector: RedisConnector::Cluster(client),
+                con: RedisConnection::Cluster(con),
+            });
+        }
+        let mut info = conf.url.as_deref().unwrap_or_default().into_connection_info()?;
+        if username.is_some() {
+            info.redis.username = username;
+        }
+        if password.is_some() {
+            info.redis.password = password;
+        }
+        let client = match certificates {
+            Some(certificates) => redis::Client::build_with_tls(info, certificates)?,
+            None => redis::Client::open(info)?,
+        };
+        Self::new(client).await
+    }
+    /// connect to the master given by the sentinels, the credentials and
+    /// certificates being the ones of the master
+    async fn connect_sentinel(
+        sentinel_conf: &SentinelConf,
+        username: Option<String>,
+        password: Option<String>,
+        certificates: Option<TlsCertificates>,
+    ) -> Result<SentinelConnection, RescError> {
+        let mut addrs = Vec::new();
+        let mut sentinel_username = None;
+        let mut sentinel_password = None;
+        for node in &sentinel_conf.nodes {
+            let info = node.as_str().into_connection_info()?;
+            addrs.push(info.addr);
+            // credentials given in the URLs of the sentinels
+            sentinel_username = sentinel_username.or(info.redis.username);
+            sentinel_password = sentinel_password.or(info.redis.password);
+        }
+        if let Some(secret) = &sentinel_conf.username {
+            sentinel_username = Some(secret.read()?);
+        }
+        if let Some(secret) = &sentinel_conf.password {
+            sentinel_password = Some(secret.read()?);
+        }
+        let tls_sentinels = addrs.iter().all(|addr| matches!(addr, ConnectionAddr::TcpTls { .. }));
+        let mut builder = SentinelClientBuilder::new(
+            addrs,
+            sentinel_conf.master.clone(),
+            SentinelServerType::Master,
+        )?;
+        if let Some(username) = username {
+            builder = builder.set_client_to_redis_username(username);
+        }
+        if let Some(password) = password {
+            builder = builder.set_client_to_redis_password(password);
+        }
+        if let Some(username) = sentinel_username {
+            builder = builder.set_client_to_sentinel_username(username);
+        }
+        if let Some(password) = sentinel_password {
+            builder = builder.set_client_to_sentinel_password(password);
+        }
+        if let Some(certificates) = certificates {
+            if tls_sentinels {
+                builder = builder.set_client_to_sentinel_certificates(certificates.clone());
+            }
+            builder = builder
+                .set_client_to_redis_tls_mode(TlsMode::Secure)
+                .set_client_to_redis_certificates(certificates);
+        }
+        Ok(SentinelConnection::new(builder.build()?).await?)
+    }
+    pub fn con(&self) -> RedisConnection {
+        self.con.clone()
+    }
+}
+
+/// BRPOPLPUSH blocks the connection it's sent on, so
+/// every taker has its own connection
+struct RedisTaker<C> {
+    con: C,
+}
+
+#[async_trait]
+impl<C: ConnectionLike + Send + Sync> EventTaker for RedisTaker<C> {
+    async fn take(
+        &mut self,
+        input_queue: &str,
+        taken_queue: &str,
+        timeout: f64,
+    ) -> Result<Option<String>, RescError> {
+        Ok(self.con.brpoplpush(input_queue, taken_queue, timeout).await?)
+    }
+}
+
+#[async_trait]
+impl QueueBackend for RedisBackend {
+    async fn taker(&self) -> Result<Box<dyn EventTaker>, RescError> {
+        Ok(match &self.connector {
+            RedisConnector::Single(client) => {
+                let con = client.get_multiplexed_async_connection().await?;
+                Box::new(RedisTaker { con })
+            }
+            RedisConnector::Sentinel(sentinel) => {
+                // a new taker is made after a connection loss, so
+                // the sentinels are asked for the current master
+                let client = sentinel.master_client().await?;
+                let con = client.get_multiplexed_async_connection().await?;
+                Box::new(RedisTaker { con })
+            }
+            RedisConnector::Cluster(client) => {
+                let con = client.get_async_connection().await?;
+                Box::new(RedisTaker { con })
+            }
+        })
+    }
+    async fn push(&self, queue: &str, value: &str) -> Result<(), RescError> {
+        let _: () = self.con().lpush(queue, value).await?;
+        Ok(())
+    }
+    async fn push_deduplicated(
+        &self,
+        queue: &str,
+        set: &str,
+        value: &str,
+        score: f64,
+    ) -> Result<bool, RescError> {
+        let pushed: i32 = PUSH_DEDUPLICATED
+            .key(queue)
+            .key(set)
+            .arg(value)
+            .arg(score)
+            .invoke_async(&mut self.con())
+            .await?;
+        Ok(pushed == 1)
+    }
+    async fn move_tail(&self, from: &str, to: &str) -> Result<Option<String>, RescError> {
+        Ok(self.con().rpoplpush(from, to).await?)
+    }
+    async fn remove(&self, queue: &str, value: &str) -> Result<(), RescError> {
+        let _: () = self.con().lrem(queue, 1, value).await?;
+        Ok(())
+    }
+    async fn score(&self, set: &str, member: &str) -> Result<Option<f64>, RescError> {
+        Ok(self.con().zscore(set, member).await?)
+    }
+    async fn add_to_set(&self, set: &str, member: &str, score: f64) -> Result<(), RescError> {
+        let _: () = self.con().zadd(set, member, score).await?;
+        Ok(())
+    }
+    async fn remove_from_set(&self, set: &str, member: &str) -> Result<bool, RescError> {
+        let removed: usize = self.con().zrem(set, member).await?;
+        Ok(removed > 0)
+    }
+    async fn increment(&self, key: &str, ttl: Duration) -> Result<u64, RescError> {
+        let (value, ()): (u64, ()) = redis::pipe()
+            .atomic()
+            .incr(key, 1)
+            .pexpire(key, ttl.as_millis() as i64)
+            .query_async(&mut self.con())
+            .await?;
+        Ok(value)
+    }
+    async fn delete(&self, key: &str) -> Result<(), RescError> {
+        let _: () = self.con().del(key).await?;
+        Ok(())
+    }
+    async fn set_expiring(&self, key: &str, value: &str, ttl: Duration) -> Result<(), RescError> {
+        let _: () = self.con().pset_ex(key, value, ttl.as_millis() as u64).await?;
+        Ok(())
+    }
+    async fn requeue_orphans(
+        &self,
+        taken_queue: &str,
+        queue: &str,
+        set: Option<&str>,
+        heartbeat: &str,
+        score: f64,
+    ) -> Result<Vec<String>, RescError> {
+        let mut invocation = REQUEUE_ORPHANS.key(taken_queue);
+        invocation.key(heartbeat).key(queue);
+        if let Some(set) = set {
+            invocation.key(set);
+        }
+        Ok(invocation
+            .arg(score)
+            .arg(MAX_REQUEUES_PER_CHECK)
+            .invoke_async(&mut self.con())
+            .await?)
+    }
+    async fn lease(&self, queue: &str, leases: &str, deadline: f64) -> Result<Option<String>, RescError> {
+        Ok(LEASE
+            .key(queue)
+            .key(leases)
+            .arg(deadline)
+            .invoke_async(&mut self.con())
+            .await?)
+    }
+    async fn extend_lease(&self, leases: &str, task: &str, deadline: f64) -> Result<bool, RescError> {
+        let extended: usize = EXTEND_LEASE
+            .key(leases)
+            .arg(deadline)
+            .arg(task)
+            .invoke_async(&mut self.con())
+            .await?;
+        Ok(extended > 0)
+    }
+    async fn requeue_expired_leases(
+        &self,
+        leases: &str,
+        queue: &str,
+        set: Option<&str>,
+        now: f64,
+    ) -> Result<Vec<String>, RescError> {
+        let mut invocation = REQUEUE_EXPIRED_LEASES.key(leases);
+        invocation.key(queue);
+        if let Some(set) = set {
+            invocation.key(set);
+        }
+        Ok(invocation
+            .arg(now)
+            .arg(MAX_REQUEUES_PER_CHECK)
+            .invoke_async(&mut self.con())
+            .await?)
+    }
+    async fn live_members(&self, set: &str, min_score: f64) -> Result<Vec<String>, RescError> {
+        let ((), members): ((), Vec<String>) = redis::pipe()
+            .atomic()
+            .cmd("ZREMRANGEBYSCORE").arg(set).arg("-inf").arg(format!("({}", min_score))
+            .zrangebyscore(set, min_score, "+inf")
+            .query_async(&mut self.con())
+            .await?;
+        Ok(members)
+    }
+    async fn lock(&self, key: &str, owner: &str, ttl: Duration) -> Result<bool, RescError> {
+        let locked: usize = LOCK
+            .key(key)
+            .arg(owner)
+            .arg(ttl.as_millis() as u64)
+            .invoke_async(&mut self.con())
+            .await?;
+        Ok(locked > 0)
+    }
+    async fn unlock(&self, key: &str, owner: &str) -> Result<(), RescError> {
+        let _: usize = UNLOCK
+            .key(key)
+            .arg(owner)
+            .invoke_async(&mut self.con())
+            .await?;
+        Ok(())
+    }
+    async fn arrive_in_join(
+        &self,
+        key: &str,
+        member: &str,
+        expected: &[String],
+        ttl: Duration,
+    ) -> Result<bool, RescError> {
+        let complete: usize = ARRIVE_IN_JOIN
+            .key(key)
+            .arg(member)
+            .arg(now_secs())
+            .arg(ttl.as_millis() as u64)
+            .arg(JOIN_COMPLETER_FIELD)
+            .arg(expected)
+            .invoke_async(&mut self.con())
+            .await?;
+        Ok(complete > 0)
+    }
+    async fn debounce(
+        &self,
+        due: &str,
+        pending: &str,
+        key: &str,
+        event: &str,
+        due_time: f64,
+    ) -> Result<(), RescError> {
+        let _: () = redis::pipe()
+            .atomic()
+            .hset(pending, key, event).ignore()
+            .zadd_options(due, key, due_time, &redis::SortedSetAddOptions::add_only()).ignore()
+            .query_async(&mut self.con())
+            .await?;
+        Ok(())
+    }
+    async fn close_windows(
+        &self,
+        due: &str,
+        pending: &str,
+        firing: &str,
+        now: f64,
+        max: usize,
+    ) -> Result<Vec<(String, String)>, RescError> {
+        let closed: Vec<String> = CLOSE_WINDOWS
+            .key(due)
+            .key(pending)
+            .key(firing)
+            .arg(now)
+            .arg(max)
+            .invoke_async(&mut self.con())
+            .await?;
+        Ok(closed.chunks(2)
+            .filter_map(|pair| match pair {
+                [key, event] => Some((key.clone(), event.clone())),
+                _ => None,
+            })
+            .collect())
+    }
+    async fn hash_entries(&self, hash: &str) -> Result<Vec<(String, String)>, RescError> {
+        let entries: HashMap<String, String> = self.con().hgetall(hash).await?;
+        Ok(entries.into_iter().collect())
+    }
+    async fn remove_from_hash(&self, hash: &str, key: &str) -> Result<(), RescError> {
+        let _: () = self.con().hdel(hash, key).await?;
+        Ok(())
+    }
+    async fn release_join(&self, key: &str, member: &str) -> Result<(), RescError> {
+        let _: usize = RELEASE_JOIN
+            .key(key)
+            .arg(JOIN_COMPLETER_FIELD)
+            .arg(member)
+            .invoke_async(&mut self.con())
+            .await?;
+        Ok(())
+    }
+    async fn complete_join(&self, key: &str, member: &str) -> Result<(), RescError> {
+        let _: usize = COMPLETE_JOIN
+            .key(key)
+            .arg(JOIN_COMPLETER_FIELD)
+            .arg(member)
+            .invoke_async(&mut self.con())
+            .await?;
+        Ok(())
+    }
+    async fn publish(&self, channel: &str, message: &str) -> Result<(), RescError> {
+        let _: () = self.con().publish(channel, message).await?;
+        Ok(())
+    }
+    fn redis(&self) -> Option<RedisConnection> {
+        Some(self.con())
+    }
+}
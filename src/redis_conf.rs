@@ -0,0 +1,244 @@
+use {
+    crate::*,
+    redis::{ClientTlsConfig, TlsCertificates},
+    serde::Deserialize,
+    std::fmt,
+};
+
+/// Redis access configuration: either the URL of a single
+/// server, a master watched by sentinels, or a cluster
+#[derive(Debug, Clone, Deserialize)]
+pub struct RedisConf {
+    /// the URL of a single server, for example "redis://127.0.0.1/",
+    /// or "rediss://redis.example.com/" with TLS
+    pub url: Option<String>,
+    /// the sentinels watching the master
+    pub sentinel: Option<SentinelConf>,
+    /// the nodes of a cluster
+    pub cluster: Option<ClusterConf>,
+    /// the ACL user, overriding the one of the URLs
+    pub username: Option<Secret>,
+    /// the password, overriding the one of the URLs
+    pub password: Option<Secret>,
+    /// the certificates of TLS connections
+    pub tls: Option<TlsConf>,
+}
+
+/// A master watched by sentinels
+#[derive(Debug, Clone, Deserialize)]
+pub struct SentinelConf {
+    /// the name of the master, as known by the sentinels
+    pub master: String,
+    /// the URLs of the sentinels, for example "redis://10.0.0.1:26379"
+    pub nodes: Vec<String>,
+    /// the ACL user of the sentinels, when it's not the one of the master
+    pub username: Option<Secret>,
+    /// the password of the sentinels, when it's not the one of the master
+    pub password: Option<Secret>,
+}
+
+/// A Redis Cluster
+#[derive(Debug, Clone, Deserialize)]
+pub struct ClusterConf {
+    /// the URLs of some nodes of the cluster, from which
+    /// the other ones are discovered
+    pub nodes: Vec<String>,
+}
+
+/// Certificates of TLS connections, as paths to PEM files.
+///
+/// TLS is used for the "rediss://" URLs, and for the master
+/// given by the sentinels when this configuration is present
+#[derive(Debug, Clone, Deserialize)]
+pub struct TlsConf {
+    /// the certificate of the authority signing the certificates of
+    /// the servers, when it's not in the trust store of the system
+    pub ca_cert: Option<String>,
+    /// the certificate of the client, when the servers check it
+    pub client_cert: Option<String>,
+    /// the private key of the client certificate
+    pub client_key: Option<String>,
+}
+
+/// A value which shouldn't be written in the configuration
+/// file, so that this file can be shared or checked in.
+///
+/// It's written `{ env: REDIS_PASSWORD }` to read it from an
+/// environment variable, `{ file: /run/secrets/redis-password }`
+/// to read it from a file, or `{ value: ... }`.
+#[derive(Clone, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Secret {
+    Env(String),
+    File(String),
+    Value(String),
+}
+
+impl fmt::Debug for Secret {
+    // the value mustn't end in logs
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Env(name) => write!(f, "Env({:?})", name),
+            Self::File(path) => write!(f, "File({:?})", path),
+            Self::Value(_) => write!(f, "Value(..)"),
+        }
+    }
+}
+
+impl Secret {
+    /// read the secret, the trailing newline of a file being removed
+    pub fn read(&self) -> Result<String, ConfError> {
+        match self {
+            Self::Env(name) => std::env::var(name)
+                .map_err(|_| ConfError::UnreadableSecret(format!("no environment variable {:?}", name))),
+            Self::File(path) => std::fs::read_to_string(path)
+                .map(|value| value.trim_end_matches(['\r', '\n']).to_owned())
+                .map_err(|e| ConfError::UnreadableSecret(format!("can't read {:?}: {}", path, e))),
+            Self::Value(value) => Ok(value.clone()),
+        }
+    }
+}
+
+impl TlsConf {
+    /// check the client certificate comes with its key
+    pub fn validate(&self) -> Result<(), ConfError> {
+        if self.client_cert.is_some() != self.client_key.is_some() {
+            return Err(ConfError::InvalidRedis(
+                "client_cert and client_key go together".to_owned()
+            ));
+        }
+        Ok(())
+    }
+    /// read the certificate files
+    pub fn certificates(&self) -> Result<TlsCertificates, ConfError> {
+        let client_tls = match (&self.client_cert, &self.client_key) {
+            (Some(cert), Some(key)) => Some(ClientTlsConfig {
+                client_cert: read_pem(cert)?,
+                client_key: read_pem(key)?,
+            }),
+            _ => None,
+        };
+        let root_cert = self.ca_cert.as_deref().map(read_pem).transpose()?;
+        Ok(TlsCertificates { client_tls, root_cert })
+    }
+}
+
+fn read_pem(path: &str) -> Result<Vec<u8>, ConfError> {
+    std::fs::read(path)
+        .map_err(|e| ConfError::InvalidRedis(format!("can't read {:?}: {}", path, e)))
+}
+
+impl RedisConf {
+    /// the configuration of a single server
+    pub fn new(url: &str) -> Self {
+        Self {
+            url: Some(url.to_owned()),
+            sentinel: None,
+            cluster: None,
+            username: None,
+            password: None,
+            tls: None,
+        }
+    }
+    /// read the username and password, if they're configured
+    pub fn credentials(&self) -> Result<(Option<String>, Option<String>), ConfError> {
+        Ok((
+            self.username.as_ref().map(Secret::read).transpose()?,
+            self.password.as_ref().map(Secret::read).transpose()?,
+        ))
+    }
+    pub fn is_cluster(&self) -> bool {
+        self.cluster.is_some()
+    }
+    /// check there's exactly one way to connect
+    pub fn validate(&self) -> Result<(), ConfError> {
+        let ways = [self.url.is_some(), self.sentinel.is_some(), self.cluster.is_some()];
+        if ways.iter().filter(|way| **way).count() != 1 {
+            return Err(ConfError::InvalidRedis(
+                "exactly one of url, sentinel and cluster is needed".to_owned()
+            ));
+        }
+        if self.sentinel.as_ref().is_some_and(|sentinel| sentinel.nodes.is_empty()) {
+            return Err(ConfError::InvalidRedis("no sentinel node".to_owned()));
+        }
+        if self.cluster.as_ref().is_some_and(|cluster| cluster.nodes.is_empty()) {
+            return Err(ConfError::InvalidRedis("no cluster node".to_owned()));
+        }
+        if let Some(tls) = &self.tls {
+            tls.validate()?;
+            // the sentinels may be reached without TLS, but not the servers
+            let server_urls = self.url.iter()
+                .chain(self.cluster.iter().flat_map(|cluster| &cluster.nodes));
+            for url in server_urls {
+                if !url.starts_with("rediss://") {
+                    return Err(ConfError::InvalidRedis(format!(
+                        "TLS certificates need rediss:// URLs, not {:?}", url,
+                    )));
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+/// the part of a key a Redis Cluster hashes to find its slot: the
+/// content of its first non empty hash tag ("{...}"), or the whole key
+pub fn hashed_part(key: &str) -> &str {
+    if let Some(open) = key.find('{') {
+        if let Some(len) = key[open + 1..].find('}') {
+            if len > 0 {
+                return &key[open + 1..open + 1 + len];
+            }
+        }
+    }
+    key
+}
+
+/// check keys used together in an atomic operation are in the same
+/// slot of a cluster, which is sure when they have the same hash tag
+pub fn check_same_slot(keys: &[&str]) -> Result<(), ConfError> {
+    let Some(first) = keys.first() else {
+        return Ok(());
+    };
+    for key in &keys[1..] {
+        if hashed_part(key) != hashed_part(first) {
+            return Err(ConfError::CrossSlot(first.to_string(), key.to_string()));
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn redis_conf(hjson: &str) -> RedisConf {
+        deser_hjson::from_str(hjson).unwrap()
+    }
+
+    #[test]
+    fn tls_needs_rediss_urls() {
+        redis_conf(r#"{ url: "rediss://10.0.0.1/", tls: {} }"#).validate().unwrap();
+        assert!(redis_conf(r#"{ url: "redis://10.0.0.1/", tls: {} }"#).validate().is_err());
+        redis_conf(r#"{
+            cluster: { nodes: [ "rediss://10.0.0.1/", "rediss://10.0.0.2/" ] }
+            tls: {}
+        }"#).validate().unwrap();
+        assert!(redis_conf(r#"{
+            cluster: { nodes: [ "rediss://10.0.0.1/", "redis://10.0.0.2/" ] }
+            tls: {}
+        }"#).validate().is_err());
+        redis_conf(r#"{
+            sentinel: { master: "mymaster", nodes: [ "redis://10.0.0.1:26379" ] }
+            tls: {}
+        }"#).validate().unwrap();
+    }
+
+    #[test]
+    fn hash_tags() {
+        assert_eq!(hashed_part("{tasks}/scheduled"), "tasks");
+        assert_eq!(hashed_part("a/{}/{b}"), "a/{}/{b}");
+        assert!(check_same_slot(&["{tasks}/scheduled", "{tasks}/todo"]).is_ok());
+        assert!(check_same_slot(&["{tasks}/scheduled", "tasks/todo"]).is_err());
+    }
+}
@@ -0,0 +1,188 @@
+use {
+    log::*,
+    redis::{
+        aio::{ConnectionLike, ConnectionManager},
+        cluster_async::ClusterConnection,
+        sentinel::SentinelClient,
+        Client, Cmd, ConnectionAddr, ErrorKind, Pipeline, RedisError, RedisFuture, Value,
+    },
+    std::sync::{Arc, RwLock},
+    tokio::sync::Mutex,
+};
+
+/// A connection to Redis, whatever its deployment: a single
+/// server, a master watched by sentinels, or a cluster.
+///
+/// It's cheap to clone, the clones sharing the same connection.
+#[derive(Clone)]
+pub enum RedisConnection {
+    Single(ConnectionManager),
+    Sentinel(SentinelConnection),
+    /// the cluster connection follows the slots as they move
+    /// and the replicas as they're promoted
+    Cluster(ClusterConnection),
+}
+
+impl ConnectionLike for RedisConnection {
+    fn req_packed_command<'a>(&'a mut self, cmd: &'a Cmd) -> RedisFuture<'a, Value> {
+        match self {
+            Self::Single(con) => con.req_packed_command(cmd),
+            Self::Sentinel(con) => con.req_packed_command(cmd),
+            Self::Cluster(con) => con.req_packed_command(cmd),
+        }
+    }
+    fn req_packed_commands<'a>(
+        &'a mut self,
+        cmd: &'a Pipeline,
+        offset: usize,
+        count: usize,
+    ) -> RedisFuture<'a, Vec<Value>> {
+        match self {
+            Self::Single(con) => con.req_packed_commands(cmd, offset, count),
+            Self::Sentinel(con) => con.req_packed_commands(cmd, offset, count),
+            Self::Cluster(con) => con.req_packed_commands(cmd, offset, count),
+        }
+    }
+    fn get_db(&self) -> i64 {
+        match self {
+            Self::Single(con) => con.get_db(),
+            Self::Sentinel(con) => con.get_db(),
+            Self::Cluster(con) => con.get_db(),
+        }
+    }
+}
+
+/// The connection to the master of a group watched by sentinels.
+///
+/// When a command fails in a way suggesting a failover, the sentinels
+/// are asked for the master again, and the connection switches to
+/// the new one. The command is sent again when it surely wasn't run.
+#[derive(Clone)]
+pub struct SentinelConnection {
+    sentinel: Arc<Mutex<SentinelClient>>,
+    /// the address of the master, and the connection to it
+    master: Arc<RwLock<(ConnectionAddr, ConnectionManager)>>,
+}
+
+impl SentinelConnection {
+    pub async fn new(sentinel: SentinelClient) -> Result<Self, RedisError> {
+        let sentinel = Arc::new(Mutex::new(sentinel));
+        let client = sentinel.lock().await.async_get_client().await?;
+        let addr = client.get_connection_info().addr.clone();
+        let con = ConnectionManager::new(client).await?;
+        Ok(Self {
+            sentinel,
+            master: Arc::new(RwLock::new((addr, con))),
+        })
+    }
+
+    /// a client for the current master, as given by the sentinels
+    pub async fn master_client(&self) -> Result<Client, RedisError> {
+        self.sentinel.lock().await.async_get_client().await
+    }
+
+    /// ask the sentinels for the master, and switch to
+    /// it if it's not the current one
+    pub async fn follow_master(&self) -> Result<(), RedisError> {
+        let client = self.master_client().await?;
+        let addr = client.get_connection_info().addr.clone();
+        if self.master.read().unwrap().0 == addr {
+            return Ok(());
+        }
+        info!("switching to the new Redis master {}", &addr);
+        let con = ConnectionManager::new(client).await?;
+        *self.master.write().unwrap() = (addr, con);
+        Ok(())
+    }
+
+    fn con(&self) -> ConnectionManager {
+        self.master.read().unwrap().1.clone()
+    }
+
+    /// handle a failed command, returning whether it can be sent again
+    async fn after_error(&self, e: &RedisError) -> bool {
+        if !suggests_failover(e) {
+            return false;
+        }
+        warn!("Redis master unavailable ({}), asking the sentinels", e);
+        if let Err(e) = self.follow_master().await {
+            warn!("can't get the Redis master from the sentinels: {}", e);
+            return false;
+        }
+        surely_not_run(e)
+    }
+}
+
+/// whether the error of a command suggests the master changed
+fn suggests_failover(e: &RedisError) -> bool {
+    e.kind() == ErrorKind::ReadOnly
+        || e.is_connection_refusal()
+        || e.is_connection_dropped()
+        || e.is_unrecoverable_error()
+}
+
+/// whether the failed command surely wasn't run, as it was
+/// rejected by a replica or not sent, so that it can be sent again
+fn surely_not_run(e: &RedisError) -> bool {
+    e.kind() == ErrorKind::ReadOnly || e.is_connection_refusal()
+}
+
+impl ConnectionLike for SentinelConnection {
+    fn req_packed_command<'a>(&'a mut self, cmd: &'a Cmd) -> RedisFuture<'a, Value> {
+        Box::pin(async move {
+            let mut con = self.con();
+            match con.req_packed_command(cmd).await {
+                Err(e) if self.after_error(&e).await => {
+                    let mut con = self.con();
+                    con.req_packed_command(cmd).await
+                }
+                result => result,
+            }
+        })
+    }
+    fn req_packed_commands<'a>(
+        &'a mut self,
+        cmd: &'a Pipeline,
+        offset: usize,
+        count: usize,
+    ) -> RedisFuture<'a, Vec<Value>> {
+        Box::pin(async move {
+            let mut con = self.con();
+            match con.req_packed_commands(cmd, offset, count).await {
+                Err(e) if self.after_error(&e).await => {
+                    let mut con = self.con();
+                    con.req_packed_commands(cmd, offset, count).await
+                }
+                result => result,
+            }
+        })
+    }
+    fn get_db(&self) -> i64 {
+        self.con().get_db()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use {
+        super::*,
+        std::io,
+    };
+
+    #[test]
+    fn only_commands_surely_not_run_are_sent_again() {
+        let read_only = RedisError::from((ErrorKind::ReadOnly, "You can't write against a read only replica."));
+        assert!(suggests_failover(&read_only));
+        assert!(surely_not_run(&read_only));
+        let refused = RedisError::from(io::Error::from(io::ErrorKind::ConnectionRefused));
+        assert!(suggests_failover(&refused));
+        assert!(surely_not_run(&refused));
+        // the command may have been run before the connection dropped
+        let dropped = RedisError::from(io::Error::from(io::ErrorKind::ConnectionReset));
+        assert!(suggests_failover(&dropped));
+        assert!(!surely_not_run(&dropped));
+        // errors of the command itself don't concern the sentinels
+        let wrong_type = RedisError::from((ErrorKind::TypeError, "WRONGTYPE"));
+        assert!(!suggests_failover(&wrong_type));
+    }
+}
@@ -0,0 +1,142 @@
+use {
+    crate::*,
+    serde::Deserialize,
+    std::{
+        collections::HashMap,
+        time::Duration,
+    },
+};
+
+/// the prefix of the events workers push when a task failed
+pub const FAILED_EVENT_PREFIX: &str = "failed/";
+
+/// the event a worker pushes when a task failed, for example
+/// "failed/2/trt/plantA/42" for the second failed attempt
+/// of "trt/plantA/42"
+pub fn failed_event(task: &str, attempt: u64) -> String {
+    format!("{}{}/{}", FAILED_EVENT_PREFIX, attempt, task)
+}
+
+/// parse a failed event into the task and the attempt
+pub fn parse_failed_event(event: &str) -> Option<(&str, u64)> {
+    let (attempt, task) = event.strip_prefix(FAILED_EVENT_PREFIX)?.split_once('/')?;
+    Some((task, attempt.parse().ok()?))
+}
+
+/// The retry policy of a maker: the task is made again, with
+/// an exponential backoff, until `max_attempts` attempts failed.
+/// It's then pushed to the dead letter queue.
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+pub struct Retry {
+
+    /// the pattern giving the number of failed attempts
+    #[serde(default = "Retry::default_attempt")]
+    pub attempt: Pattern,
+
+    /// the max number of attempts
+    #[serde(default = "Retry::default_max_attempts")]
+    pub max_attempts: u64,
+
+    /// the delay before the second attempt, doubled
+    /// for every following one
+    #[serde(default = "Retry::default_backoff", deserialize_with = "deserialize_duration")]
+    pub backoff: Duration,
+
+    /// the max delay between two attempts
+    #[serde(default = "Retry::default_max_backoff", deserialize_with = "deserialize_duration")]
+    pub max_backoff: Duration,
+
+    /// the queue where the task is pushed when it failed too
+    /// many times, by default the queue followed by "/dead-letter"
+    pub dead_letter: Option<Pattern>,
+
+}
+
+impl Retry {
+    pub fn default_attempt() -> Pattern {
+        Pattern::from("${attempt}")
+    }
+    pub fn default_max_attempts() -> u64 {
+        5
+    }
+    pub fn default_backoff() -> Duration {
+        Duration::from_secs(10)
+    }
+    pub fn default_max_backoff() -> Duration {
+        Duration::from_secs(60 * 60)
+    }
+
+    /// the number of failed attempts
+    pub fn attempt(&self, props: &HashMap<String, String>) -> Result<u64, RescError> {
+        let attempt = self.attempt.inject(props);
+        attempt.parse().map_err(|_| RescError::InvalidAttempt(attempt))
+    }
+
+    /// the delay before the next attempt, after `attempt` failed ones
+    pub fn delay(&self, attempt: u64) -> Duration {
+        let factor = 2u32.saturating_pow(attempt.saturating_sub(1).min(31) as u32);
+        self.backoff.saturating_mul(factor).min(self.max_backoff)
+    }
+
+    /// the dead letter queue, for a task which would have been
+    /// pushed to `queue`
+    pub fn dead_letter(&self, queue: &str, props: &HashMap<String, String>) -> String {
+        match &self.dead_letter {
+            Some(pattern) => pattern.inject(props),
+            None => format!("{}/dead-letter", queue),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn retry() -> Retry {
+        Retry {
+            attempt: Retry::default_attempt(),
+            max_attempts: 4,
+            backoff: Duration::from_secs(10),
+            max_backoff: Duration::from_secs(30),
+            dead_letter: None,
+        }
+    }
+
+    #[test]
+    fn failed_events() {
+        let event = failed_event("trt/plantA/42", 2);
+        assert_eq!(event, "failed/2/trt/plantA/42");
+        assert_eq!(parse_failed_event(&event), Some(("trt/plantA/42", 2)));
+        assert_eq!(parse_failed_event("trt/plantA/42"), None);
+        assert_eq!(parse_failed_event("failed/x/trt"), None);
+    }
+
+    #[test]
+    fn exponential_backoff() {
+        let retry = retry();
+        assert_eq!(retry.delay(1), Duration::from_secs(10));
+        assert_eq!(retry.delay(2), Duration::from_secs(20));
+        assert_eq!(retry.delay(3), Duration::from_secs(30));
+        assert_eq!(retry.delay(u64::MAX), Duration::from_secs(30));
+    }
+
+    #[test]
+    fn retried_then_dead_lettered() {
+        let maker = Maker::new("${task}", "todo").retry(retry());
+        let rule = Rule::builder("retry", r"^failed/(?P<attempt>\d+)/(?P<task>.+)$")
+            .make(maker)
+            .build()
+            .unwrap();
+        let mut results = Vec::new();
+        let props = rule.props(&failed_event("trt/1", 3)).unwrap();
+        rule.makers.make(&props, &mut results).unwrap();
+        assert_eq!(results[0].task, "trt/1");
+        assert_eq!(results[0].queue, "todo");
+        assert!(results[0].due.unwrap() >= now_secs() + 29.0);
+        let props = rule.props(&failed_event("trt/1", 4)).unwrap();
+        rule.makers.make(&props, &mut results).unwrap();
+        assert_eq!(results[1].task, "trt/1");
+        assert_eq!(results[1].queue, "todo/dead-letter");
+        assert_eq!(results[1].due, None);
+    }
+}
@@ -34,6 +34,28 @@ pub struct Rule {
     #[serde(alias = "make")]
     pub makers: Makers,
 
+    /// An optional debouncing: when set, the rule doesn't apply
+    /// immediately but once the window is closed, on the last
+    /// event having the same key
+    pub debounce: Option<Debounce>,
+
+    /// An optional join: when set, the rule only applies once
+    /// all the expected events arrived
+    pub join: Option<Join>,
+
+}
+
+/// Regexes can't be compared, so rules are compared
+/// on the source of their regex
+impl PartialEq for Rule {
+    fn eq(&self, other: &Self) -> bool {
+        self.name == other.name
+            && self.on_regex.as_str() == other.on_regex.as_str()
+            && self.fetchers == other.fetchers
+            && self.makers == other.makers
+            && self.debounce == other.debounce
+            && self.join == other.join
+    }
 }
 
 impl Rule {
@@ -43,25 +65,50 @@ impl Rule {
     pub fn is_match(&self, task: &str) -> bool {
         self.on_regex.is_match(task)
     }
-    /// Assuming the rule matches, computes the rule results
-    /// (there's only one RuleResult when no fetcher is involved)
-    pub fn results(&self, task: &str) -> Result<Vec<RuleResult>, RescError> {
-        // props will contain the token usable for generating
-        // the task name, output queue and output set
+    /// Extracts the properties usable in patterns: the input
+    /// task and the named groups of the "on" regex, or None
+    /// when the rule doesn't match
+    pub fn props(&self, task: &str) -> Option<HashMap<String, String>> {
+        let caps = self.on_regex.captures(task)?;
         let mut props: HashMap<String, String> = HashMap::new();
         props.insert("input_task".to_owned(), task.to_owned());
-        let caps = self.on_regex.captures(task).unwrap();
-        let mut results = Vec::new();
         for groupname in self.on_regex.capture_names().flatten() {
             if let Some(value) = caps.name(groupname) {
                 props.insert(groupname.to_string(), value.as_str().to_string());
             }
         }
+        Some(props)
+    }
+    /// Same as `props`, but with an error when the rule doesn't match
+    pub fn matching_props(&self, task: &str) -> Result<HashMap<String, String>, RescError> {
+        self.props(task)
+            .ok_or_else(|| RescError::NotMatching(self.name.clone(), task.to_owned()))
+    }
+    /// Computes the rule results, or fails when the rule doesn't match
+    /// (there's only one RuleResult when no fetcher is involved)
+    pub async fn results(&self, task: &str) -> Result<Vec<RuleResult>, RescError> {
+        self.results_with_fetches(task, &mut Vec::new()).await
+    }
+    /// Same as `results`, but also reports the queries
+    /// of the fetchers
+    pub async fn results_with_fetches(
+        &self,
+        task: &str,
+        fetches: &mut Vec<Fetched>,
+    ) -> Result<Vec<RuleResult>, RescError> {
+        // props will contain the token usable for generating
+        // the task name, output queue and output set
+        let props = self.matching_props(task)?;
+        let mut results = Vec::new();
         if !self.fetchers.is_empty() {
             // if there are fetchers, we'll fetch all the possible results
             // and generate a ruleresult per fetchresult
             for fetcher in &self.fetchers {
-                let fetch_results = fetcher.results(&props)?;
+                let fetch_results = fetcher.results(&props).await?;
+                fetches.push(Fetched {
+                    url: fetcher.url.inject(&props),
+                    count: fetch_results.len(),
+                });
                 debug!("    -> fetch results {:#?}", &fetch_results);
                 for mut fetch_result in fetch_results {
                     // we inject the parent properties
@@ -70,11 +117,11 @@ impl Rule {
                         fetch_result.props.insert(key.clone(), value.clone());
                     }
                     trace!(" merged: {:#?}", &fetch_result.props);
-                    self.makers.make(&fetch_result.props, &mut results);
+                    self.makers.make(&fetch_result.props, &mut results)?;
                 }
             }
         } else {
-            self.makers.make(&props, &mut results);
+            self.makers.make(&props, &mut results)?;
         }
         Ok(results)
     }
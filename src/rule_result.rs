@@ -14,4 +14,8 @@ pub struct RuleResult {
     /// isn't yet in the queue
     pub set: Option<String>,
 
+    /// when the task must not be pushed immediately, the
+    /// time (in seconds since the Epoch) at which it's due
+    pub due: Option<f64>,
+
 }
@@ -0,0 +1,437 @@
+use {
+    crate::*,
+    log::*,
+    std::{
+        sync::{Arc, RwLock},
+        time::Duration,
+    },
+    tokio::{
+        sync::Mutex,
+        task::JoinHandle,
+    },
+};
+
+/// how often the configuration file is checked for
+/// modifications, when watched
+const CONF_FILE_CHECK_PERIOD: Duration = Duration::from_secs(2);
+
+/// The tasks running for a configuration
+#[derive(Default)]
+struct Components {
+    watchers: Vec<(Arc<Watcher>, JoinHandle<()>)>,
+    /// the scheduler, the schedules and the reaper, which
+    /// can be aborted at any time
+    background: Vec<JoinHandle<()>>,
+    /// the global settings the watchers were started with
+    listener_channel: String,
+    listener_format: ListenerFormat,
+    scheduled_set: String,
+    lineage: Option<LineageConf>,
+    audit: Option<AuditConf>,
+    locks: Option<LockConf>,
+    /// the instances sharing the watchers, when sharding
+    membership: Option<(Arc<Membership>, JoinHandle<()>)>,
+}
+
+/// The runner starts the watchers, the scheduler and the
+/// schedules of a configuration, and can replace them when
+/// the configuration is reloaded.
+pub struct Runner {
+    /// the file the configuration is reloaded from, if any
+    conf_path: Option<String>,
+    /// the Redis backend, needed by the scheduler and the schedules
+    redis: Option<RedisBackend>,
+    /// the backend shared by the watchers
+    backend: Arc<dyn QueueBackend>,
+    components: Mutex<Components>,
+    /// held during a whole reload, so that reloads don't
+    /// overlap, while the components are only locked
+    /// when they're read or changed
+    reloading: Mutex<()>,
+    /// the running watchers, readable while the components
+    /// are locked by a reload waiting for watchers to stop
+    watchers: RwLock<Vec<Arc<Watcher>>>,
+}
+
+impl Runner {
+
+    /// connect to Redis and start the components of the configuration.
+    ///
+    /// When the configuration was read from a file, passing its
+    /// path makes it possible to reload it.
+    pub async fn start(conf_path: Option<&str>, conf: &Conf) -> Result<Self, RescError> {
+        let redis = RedisBackend::connect(&conf.redis).await?;
+        debug!("got redis connection");
+        let backend: Arc<dyn QueueBackend> = Arc::new(redis.clone());
+        Ok(Self::launch(conf_path, Some(redis), backend, conf).await)
+    }
+
+    /// start the components of the configuration on another
+    /// backend, for example a [MemoryBackend]. The scheduler and
+    /// the schedules, which need Redis, aren't started.
+    pub async fn start_on(backend: Arc<dyn QueueBackend>, conf: &Conf) -> Self {
+        Self::launch(None, None, backend, conf).await
+    }
+
+    async fn launch(
+        conf_path: Option<&str>,
+        redis: Option<RedisBackend>,
+        backend: Arc<dyn QueueBackend>,
+        conf: &Conf,
+    ) -> Self {
+        let runner = Self {
+            conf_path: conf_path.map(|path| path.to_owned()),
+            redis,
+            backend,
+            components: Mutex::new(Components::default()),
+            reloading: Mutex::new(()),
+            watchers: RwLock::new(Vec::new()),
+        };
+        {
+            let mut components = runner.components.lock().await;
+            components.membership = runner.join(conf).await;
+            for watcher_conf in &conf.watchers {
+                let watcher = runner.start_watcher(watcher_conf, conf, &components.membership);
+                components.watchers.push(watcher);
+            }
+            debug!("all watchers started");
+            components.background = runner.start_background(conf);
+            components.listener_channel = conf.listener_channel.clone();
+            components.listener_format = conf.listener_format;
+            components.scheduled_set = conf.scheduled_set.clone();
+            components.lineage = conf.lineage.clone();
+            components.audit = conf.audit.clone();
+            components.locks = conf.locks.clone();
+            runner.publish_watchers(&components);
+        }
+        runner
+    }
+
+    /// record this instance as sharing the watchers, when sharding
+    async fn join(&self, conf: &Conf) -> Option<(Arc<Membership>, JoinHandle<()>)> {
+        let lock_conf = conf.locks.as_ref().filter(|lock_conf| lock_conf.sharding)?;
+        let membership = Arc::new(Membership::new(lock_conf, &self.backend));
+        if let Err(e) = membership.refresh().await {
+            warn!("can't record membership: {}", e);
+        }
+        let handle = tokio::spawn({
+            let membership = Arc::clone(&membership);
+            async move {
+                membership.run().await;
+            }
+        });
+        Some((membership, handle))
+    }
+
+    fn start_watcher(
+        &self,
+        watcher_conf: &WatcherConf,
+        conf: &Conf,
+        membership: &Option<(Arc<Membership>, JoinHandle<()>)>,
+    ) -> (Arc<Watcher>, JoinHandle<()>) {
+        let mut watcher = Watcher::new(watcher_conf, conf, &self.backend);
+        if let Some((membership, _)) = membership {
+            watcher = watcher.with_membership(Arc::clone(membership));
+        }
+        let watcher = Arc::new(watcher);
+        let handle = tokio::spawn({
+            let watcher = Arc::clone(&watcher);
+            async move {
+                let input_queue = watcher.input_queue().to_string();
+                if let Err(e) = watcher.run().await {
+                    error!("watcher on {:?} stopped: {}", input_queue, e);
+                }
+            }
+        });
+        (watcher, handle)
+    }
+
+    /// start the scheduler, the schedules and the reaper
+    fn start_background(&self, conf: &Conf) -> Vec<JoinHandle<()>> {
+        let mut background = Vec::new();
+        if let Some(redis) = &self.redis {
+            let mut scheduler = Scheduler::new(conf, redis);
+            background.push(tokio::spawn(async move {
+                scheduler.run().await;
+            }));
+            for schedule_conf in &conf.schedules {
+                let mut scheduled = Scheduled::new(schedule_conf, conf, redis);
+                background.push(tokio::spawn(async move {
+                    scheduled.run().await;
+                }));
+            }
+        }
+        for reaper_conf in &conf.reapers {
+            let mut reaper = Reaper::new(reaper_conf, conf, &self.backend);
+            background.push(tokio::spawn(async move {
+                reaper.run().await;
+            }));
+        }
+        background
+    }
+
+    /// update the list of the running watchers
+    fn publish_watchers(&self, components: &Components) {
+        *self.watchers.write().unwrap() = components.watchers.iter()
+            .map(|(watcher, _)| Arc::clone(watcher))
+            .collect();
+    }
+
+    /// the running watchers. During a reload, they're the
+    /// watchers of the previous configuration
+    pub async fn watchers(&self) -> Vec<Arc<Watcher>> {
+        self.watchers.read().unwrap().clone()
+    }
+
+    pub async fn watcher(&self, input_queue: &str) -> Option<Arc<Watcher>> {
+        self.watchers.read().unwrap().iter()
+            .find(|watcher| watcher.input_queue() == input_queue)
+            .map(Arc::clone)
+    }
+
+    /// read the configuration file again and apply the changes.
+    /// If the new configuration is invalid, the old one keeps running.
+    ///
+    /// Watchers whose settings are unchanged keep running, with
+    /// their rules swapped if they changed. The other ones are
+    /// stopped, after the events they handle are done, and started
+    /// again with the new settings. The scheduler, the schedules
+    /// and the reaper are always restarted.
+    ///
+    /// Changes of the Redis, metrics and admin settings are ignored.
+    pub async fn reload(&self) -> Result<(), RescError> {
+        let Some(conf_path) = &self.conf_path else {
+            return Err(RescError::NoConfFile);
+        };
+        info!("reloading configuration from {}", conf_path);
+        let conf = conf::read_file(conf_path)?;
+        self.apply(&conf).await;
+        Ok(())
+    }
+
+    /// replace the running configuration with a new one, which
+    /// is assumed valid, the same way `reload` does
+    ///
+    /// The state of the debounced rules which are removed is deleted.
+    pub async fn apply(&self, conf: &Conf) {
+        let _reloading = self.reloading.lock().await;
+        let mut components = self.components.lock().await;
+        let globals_changed = components.listener_channel != conf.listener_channel
+            || components.listener_format != conf.listener_format
+            || components.scheduled_set != conf.scheduled_set
+            || components.lineage != conf.lineage
+            || components.audit != conf.audit
+            || components.locks != conf.locks;
+        let mut old_watchers = std::mem::take(&mut components.watchers);
+        let old_debounced = debounced_keys(
+            old_watchers.iter().map(|(watcher, _)| (watcher.input_queue(), watcher.rules())),
+        );
+        let mut kept = Vec::new();
+        let mut to_start = Vec::new();
+        for watcher_conf in &conf.watchers {
+            let idx = old_watchers.iter()
+                .position(|(watcher, _)| watcher.input_queue() == watcher_conf.input_queue);
+            let Some(idx) = idx else {
+                info!("new watcher on {:?}", &watcher_conf.input_queue);
+                to_start.push(watcher_conf);
+                continue;
+            };
+            let (watcher, handle) = old_watchers.swap_remove(idx);
+            let new_watcher = Watcher::new(watcher_conf, conf, &self.backend);
+            let same_settings = !globals_changed
+                && !handle.is_finished()
+                && watcher.taken_queue() == new_watcher.taken_queue()
+                && watcher.concurrency() == new_watcher.concurrency()
+                && watcher.lease() == new_watcher.lease();
+            if same_settings {
+                if watcher.rules() != watcher_conf.rules {
+                    watcher.swap_rules(watcher_conf.rules.clone());
+                }
+                kept.push((watcher, handle));
+            } else {
+                info!("watcher on {:?} restarted with new settings", &watcher_conf.input_queue);
+                old_watchers.push((watcher, handle));
+                to_start.push(watcher_conf);
+            }
+        }
+        components.watchers = kept;
+        drop(components);
+
+        // the remaining old watchers are either removed or replaced,
+        // they must be stopped before their replacements take their
+        // queues. They finish their events without the components
+        // being locked.
+        for (watcher, _) in &old_watchers {
+            watcher.control(WatcherControl::Stop);
+        }
+        for (_, handle) in old_watchers {
+            let _ = handle.await;
+        }
+        let new_debounced = debounced_keys(
+            conf.watchers.iter().map(|w| (w.input_queue.as_str(), w.rules.clone())),
+        );
+        for (name, keys) in old_debounced {
+            if !new_debounced.iter().any(|(new_name, _)| *new_name == name) {
+                info!("forgetting the debounced events of the removed rule {:?}", name);
+                for key in keys.all() {
+                    if let Err(e) = self.backend.delete(key).await {
+                        warn!("can't delete {:?}: {}", key, e);
+                    }
+                }
+            }
+        }
+
+        let mut components = self.components.lock().await;
+        if components.locks != conf.locks {
+            // all watchers were restarted
+            if let Some((_, handle)) = components.membership.take() {
+                handle.abort();
+            }
+            components.membership = self.join(conf).await;
+        }
+        for watcher_conf in to_start {
+            let watcher = self.start_watcher(watcher_conf, conf, &components.membership);
+            components.watchers.push(watcher);
+        }
+        self.publish_watchers(&components);
+        for handle in components.background.drain(..) {
+            handle.abort();
+        }
+        components.background = self.start_background(conf);
+        components.listener_channel = conf.listener_channel.clone();
+        components.listener_format = conf.listener_format;
+        components.scheduled_set = conf.scheduled_set.clone();
+        components.lineage = conf.lineage.clone();
+        components.audit = conf.audit.clone();
+        components.locks = conf.locks.clone();
+        info!("configuration reloaded");
+    }
+
+    /// reload the configuration on every SIGHUP, forever
+    #[cfg(unix)]
+    pub async fn reload_on_sighup(self: Arc<Self>) -> Result<(), RescError> {
+        use tokio::signal::unix::{signal, SignalKind};
+        let mut hangups = signal(SignalKind::hangup())?;
+        while hangups.recv().await.is_some() {
+            info!("got SIGHUP");
+            if let Err(e) = self.reload().await {
+                error!("configuration not reloaded: {}", e);
+            }
+        }
+        Ok(())
+    }
+
+    /// reload the configuration whenever the configuration
+    /// file is modified, forever
+    pub async fn watch_conf_file(self: Arc<Self>) {
+        let Some(conf_path) = self.conf_path.clone() else {
+            return;
+        };
+        let modified = || std::fs::metadata(&conf_path)
+            .and_then(|metadata| metadata.modified())
+            .ok();
+        let mut last_modified = modified();
+        loop {
+            tokio::time::sleep(CONF_FILE_CHECK_PERIOD).await;
+            let current = modified();
+            if current.is_some() && current != last_modified {
+                last_modified = current;
+                if let Err(e) = self.reload().await {
+                    error!("configuration not reloaded: {}", e);
+                }
+            }
+        }
+    }
+
+}
+
+/// the debounced rules of watchers, identified by their input
+/// queue and name, with the keys of their state
+fn debounced_keys<'q, I>(watchers: I) -> Vec<((String, String), DebounceKeys)>
+where
+    I: Iterator<Item = (&'q str, Vec<Rule>)>,
+{
+    watchers
+        .flat_map(|(input_queue, rules)| {
+            rules.into_iter()
+                .filter(|rule| rule.debounce.is_some())
+                .map(move |rule| {
+                    let keys = DebounceKeys::new(input_queue, &rule);
+                    ((input_queue.to_owned(), rule.name), keys)
+                })
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rule(name: &str) -> Rule {
+        Rule::builder(name, r"^acq/(?P<process>\w+)$")
+            .make(Maker::new("trt/${process}", "trt/todo"))
+            .build()
+            .unwrap()
+    }
+
+    fn debounced(name: &str) -> Rule {
+        Rule::builder(name, r"^acq/(?P<process>\w+)$")
+            .make(Maker::new("trt/${process}", "trt/todo"))
+            .debounce(Duration::from_secs(60), "${process}")
+            .build()
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn reload_applies_the_differences() {
+        let old_conf = Conf::builder("redis://127.0.0.1/", "listener")
+            .watcher(WatcherConf::new("kept").rule(debounced("d")))
+            .watcher(WatcherConf::new("swapped").rule(rule("old")))
+            .watcher(WatcherConf::new("restarted").rule(rule("r")))
+            .watcher(WatcherConf::new("removed").rule(debounced("d")))
+            .build()
+            .unwrap();
+        let memory = MemoryBackend::new();
+        let runner = Runner::start_on(Arc::new(memory.clone()), &old_conf).await;
+        // pending debounced events of both debounced rules
+        for input_queue in ["kept", "removed"] {
+            let keys = DebounceKeys::new(input_queue, &debounced("d"));
+            let [due, pending, _] = keys.all();
+            memory.debounce(due, pending, "a", "acq/a", 1e12).await.unwrap();
+        }
+        let kept = runner.watcher("kept").await.unwrap();
+        let swapped = runner.watcher("swapped").await.unwrap();
+        let restarted = runner.watcher("restarted").await.unwrap();
+        let removed = runner.watcher("removed").await.unwrap();
+
+        let new_conf = Conf::builder("redis://127.0.0.1/", "listener")
+            .watcher(WatcherConf::new("kept").rule(debounced("d")))
+            .watcher(WatcherConf::new("swapped").rule(rule("new")))
+            .watcher(WatcherConf::new("restarted").concurrency(2).rule(rule("r")))
+            .watcher(WatcherConf::new("added").rule(rule("a")))
+            .build()
+            .unwrap();
+        runner.apply(&new_conf).await;
+
+        assert!(Arc::ptr_eq(&kept, &runner.watcher("kept").await.unwrap()));
+        let new_swapped = runner.watcher("swapped").await.unwrap();
+        assert!(Arc::ptr_eq(&swapped, &new_swapped));
+        assert_eq!(new_swapped.rules(), vec![rule("new")]);
+        let new_restarted = runner.watcher("restarted").await.unwrap();
+        assert!(!Arc::ptr_eq(&restarted, &new_restarted));
+        assert_eq!(restarted.status().state, WatcherState::Stopped);
+        assert_eq!(new_restarted.concurrency(), 2);
+        assert!(runner.watcher("removed").await.is_none());
+        assert_eq!(removed.status().state, WatcherState::Stopped);
+        assert!(runner.watcher("added").await.is_some());
+        assert_eq!(runner.watchers().await.len(), 4);
+
+        // only the state of the removed debounced rule is deleted
+        let kept_keys = DebounceKeys::new("kept", &debounced("d"));
+        assert_eq!(memory.set(kept_keys.all()[0]).len(), 1);
+        let removed_keys = DebounceKeys::new("removed", &debounced("d"));
+        let [due, pending, _] = removed_keys.all();
+        assert!(memory.set(due).is_empty());
+        assert!(memory.hash_entries(pending).await.unwrap().is_empty());
+    }
+}
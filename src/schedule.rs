@@ -0,0 +1,159 @@
+use {
+    crate::*,
+    chrono::{SecondsFormat, Utc},
+    lazy_static::lazy_static,
+    log::*,
+    redis::{AsyncCommands, Script},
+    serde::{Deserialize, Deserializer},
+    std::{
+        collections::HashMap,
+        str::FromStr,
+        sync::Arc,
+    },
+};
+
+lazy_static! {
+    /// Atomically record the tick (ARGV[1]) as fired in KEYS[1], unless
+    /// it, or a later one, was already fired. Returns 1 when the caller
+    /// is the one which must fire the tick
+    static ref CLAIM_TICK: Script = Script::new(r"
+        local last = redis.call('GET', KEYS[1])
+        if last and tonumber(last) >= tonumber(ARGV[1]) then
+            return 0
+        end
+        redis.call('SET', KEYS[1], ARGV[1])
+        return 1
+    ");
+}
+
+/// The configuration of a time triggered event source.
+///
+/// On every tick of its cron expression, a schedule either pushes
+/// a synthetic `event` to a `queue` (usually the input queue of
+/// a watcher) or directly generates tasks with its `make` element.
+///
+/// Patterns may use `${now}` (the tick time in seconds since the
+/// Epoch), `${now_date}` (the tick time as a RFC 3339 date) and
+/// `${schedule}` (the name of the schedule).
+#[derive(Debug, Clone, Deserialize)]
+pub struct ScheduleConf {
+
+    /// the name, which must be unique as it's used for
+    /// the lock ensuring only one instance fires a tick
+    pub name: String,
+
+    /// a cron expression with seconds, for example "0 30 2 * * *"
+    /// for every day at 02:30:00 (UTC)
+    #[serde(deserialize_with = "deserialize_cron")]
+    pub cron: cron::Schedule,
+
+    /// the synthetic event to push to the queue
+    pub event: Option<Pattern>,
+
+    /// the queue where the event is pushed
+    pub queue: Option<Pattern>,
+
+    /// the recipe for building tasks on every tick, when the
+    /// schedule doesn't go through a watcher
+    #[serde(alias = "make")]
+    pub makers: Option<Makers>,
+
+}
+
+fn deserialize_cron<'de, D>(deserializer: D) -> Result<cron::Schedule, D::Error>
+    where D: Deserializer<'de>
+{
+    let src = String::deserialize(deserializer)?;
+    cron::Schedule::from_str(&src)
+        .map_err(|e| serde::de::Error::custom(format!("invalid cron expression {:?}: {}", src, e)))
+}
+
+impl ScheduleConf {
+    /// check the schedule has either an event and a queue, or a make element
+    pub fn validate(&self) -> Result<(), ConfError> {
+        match (&self.event, &self.queue, &self.makers) {
+            (Some(_), Some(_), None) | (None, None, Some(_)) => Ok(()),
+            _ => Err(ConfError::InvalidSchedule(self.name.clone())),
+        }
+    }
+}
+
+/// Fires the events or tasks of a schedule on every tick
+/// of its cron expression.
+///
+/// Several resc instances may run the same schedule: a tick
+/// is claimed in Redis by only one of them.
+pub struct Scheduled {
+    conf: ScheduleConf,
+    con: RedisConnection,
+    dispatcher: Dispatcher,
+    lock_key: String,
+}
+
+impl Scheduled {
+
+    pub fn new(
+        schedule_conf: &ScheduleConf,
+        global_conf: &Conf,
+        backend: &RedisBackend,
+    ) -> Self {
+        Self {
+            conf: schedule_conf.clone(),
+            con: backend.con(),
+            dispatcher: Dispatcher::new(global_conf, Arc::new(backend.clone())),
+            lock_key: format!("resc/schedules/{}", &schedule_conf.name),
+        }
+    }
+
+    pub async fn run(&mut self) {
+        info!("schedule {:?} launched", &self.conf.name);
+        loop {
+            let Some(tick) = self.conf.cron.upcoming(Utc).next() else {
+                warn!("schedule {:?} has no upcoming tick", &self.conf.name);
+                return;
+            };
+            let wait = (tick - Utc::now()).to_std().unwrap_or_default();
+            tokio::time::sleep(wait).await;
+            if let Err(e) = self.fire(tick).await {
+                error!("schedule {:?} failed : {}", &self.conf.name, e);
+            }
+        }
+    }
+
+    async fn fire(&mut self, tick: chrono::DateTime<Utc>) -> Result<(), RescError> {
+        let claimed: bool = CLAIM_TICK
+            .key(&self.lock_key)
+            .arg(tick.timestamp())
+            .invoke_async(&mut self.con)
+            .await?;
+        if !claimed {
+            debug!("tick {} of schedule {:?} fired by another instance", tick, &self.conf.name);
+            return Ok(());
+        }
+        info!("<- tick {} of schedule {:?}", tick, &self.conf.name);
+        let mut props = HashMap::new();
+        props.insert("now".to_owned(), tick.timestamp().to_string());
+        props.insert("now_date".to_owned(), tick.to_rfc3339_opts(SecondsFormat::Secs, true));
+        props.insert("schedule".to_owned(), self.conf.name.clone());
+        if let (Some(event), Some(queue)) = (&self.conf.event, &self.conf.queue) {
+            let event = event.inject(&props);
+            let queue = queue.inject(&props);
+            info!("  ->  {:?} pushed to queue {:?}", &event, &queue);
+            let _: () = self.con.lpush(&queue, &event).await?;
+            self.dispatcher.notify(ListenerMessage {
+                event: Some(event),
+                queue: Some(queue),
+                ..ListenerMessage::new(ListenerEvent::Tick, &self.lock_key)
+            }).await?;
+        }
+        if let Some(makers) = &self.conf.makers {
+            let mut results = Vec::new();
+            makers.make(&props, &mut results)?;
+            for r in results {
+                self.dispatcher.dispatch(&r, &self.lock_key, &props["now_date"], None).await?;
+            }
+        }
+        Ok(())
+    }
+
+}
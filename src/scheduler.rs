@@ -0,0 +1,155 @@
+use {
+    crate::*,
+    lazy_static::lazy_static,
+    log::*,
+    redis::{Script},
+    serde::{Deserialize, Serialize},
+    std::{
+        sync::Arc,
+        time::Duration,
+    },
+};
+
+/// time between two checks of the scheduled set
+const SCHEDULER_PERIOD: Duration = Duration::from_secs(1);
+
+/// max number of tasks moved to their queues in one check
+const MAX_MOVES_PER_CHECK: usize = 1000;
+
+lazy_static! {
+    /// Atomically move the due tasks from the scheduled set (KEYS[1])
+    /// to their queue, unless they're already in their task set.
+    /// The members which aren't valid scheduled tasks are moved
+    /// to the invalid list (KEYS[2]).
+    /// Returns the moved members and the invalid ones.
+    static ref MOVE_DUE_TASKS: Script = Script::new(r"
+        local due = redis.call('ZRANGEBYSCORE', KEYS[1], '-inf', ARGV[1], 'LIMIT', 0, ARGV[2])
+        local moved = {}
+        local invalid = {}
+        for _, member in ipairs(due) do
+            redis.call('ZREM', KEYS[1], member)
+            local ok, t = pcall(cjson.decode, member)
+            if not ok or type(t) ~= 'table' or type(t['task']) ~= 'string' or type(t['queue']) ~= 'string' then
+                redis.call('LPUSH', KEYS[2], member)
+                table.insert(invalid, member)
+            else
+                local set = t['set']
+                if type(set) ~= 'string' or not redis.call('ZSCORE', set, t['task']) then
+                    if type(set) == 'string' then
+                        redis.call('ZADD', set, ARGV[1], t['task'])
+                    end
+                    redis.call('LPUSH', t['queue'], t['task'])
+                    table.insert(moved, member)
+                end
+            end
+        end
+        return {moved, invalid}
+    ");
+}
+
+/// the list where the members of the scheduled set which
+/// aren't valid scheduled tasks are parked
+pub fn invalid_scheduled_key(scheduled_set: &str) -> String {
+    format!("{}/invalid", scheduled_set)
+}
+
+/// A task waiting in the scheduled set for its due time.
+///
+/// It's stored as JSON, which makes the queue and set
+/// part of the member, so that the same task may be
+/// scheduled for different queues
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ScheduledTask {
+    pub task: String,
+    pub queue: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub set: Option<String>,
+}
+
+impl ScheduledTask {
+    pub fn from_result(r: &RuleResult) -> Self {
+        Self {
+            task: r.task.clone(),
+            queue: r.queue.clone(),
+            set: r.set.clone(),
+        }
+    }
+    /// add the task to the scheduled set, or just change its due
+    /// time if it's already there
+    pub async fn schedule(
+        &self,
+        backend: &dyn QueueBackend,
+        scheduled_set: &str,
+        due: f64,
+    ) -> Result<(), RescError> {
+        let member = serde_json::to_string(self).unwrap();
+        backend.add_to_set(scheduled_set, &member, due).await
+    }
+}
+
+/// The scheduler moves the delayed tasks, when they're due,
+/// from the scheduled set to their queue.
+///
+/// Several resc instances may run a scheduler on the same
+/// set, as the move of a task is atomic. In a Redis Cluster,
+/// this needs the queues and sets of the delayed tasks to
+/// have the hash tag of the scheduled set.
+pub struct Scheduler {
+    con: RedisConnection,
+    dispatcher: Dispatcher,
+    scheduled_set: String,
+}
+
+impl Scheduler {
+
+    pub fn new(
+        global_conf: &Conf,
+        backend: &RedisBackend,
+    ) -> Self {
+        Self {
+            con: backend.con(),
+            dispatcher: Dispatcher::new(global_conf, Arc::new(backend.clone())),
+            scheduled_set: global_conf.scheduled_set.clone(),
+        }
+    }
+
+    pub async fn run(&mut self) {
+        info!("scheduler launched on set {:?}...", &self.scheduled_set);
+        loop {
+            if let Err(e) = self.move_due_tasks().await {
+                error!("moving due tasks of {:?} failed : {}", &self.scheduled_set, e);
+            }
+            tokio::time::sleep(SCHEDULER_PERIOD).await;
+        }
+    }
+
+    async fn move_due_tasks(&mut self) -> Result<(), RescError> {
+        let now = now_secs();
+        let invalid_key = invalid_scheduled_key(&self.scheduled_set);
+        let (moved, invalid): (Vec<String>, Vec<String>) = MOVE_DUE_TASKS
+            .key(&self.scheduled_set)
+            .key(&invalid_key)
+            .arg(now)
+            .arg(MAX_MOVES_PER_CHECK)
+            .invoke_async(&mut self.con)
+            .await?;
+        for member in invalid {
+            warn!("invalid scheduled task {:?} moved to {:?}", member, &invalid_key);
+        }
+        for member in moved {
+            let Ok(scheduled) = serde_json::from_str::<ScheduledTask>(&member) else {
+                continue;
+            };
+            info!("  ->  {:?} due, pushed to queue {:?}", &scheduled.task, &scheduled.queue);
+            METRICS.tasks_pushed.inc(&[&scheduled.queue]);
+            self.dispatcher.notify(ListenerMessage {
+                task: Some(scheduled.task),
+                queue: Some(scheduled.queue),
+                set: scheduled.set,
+                ..ListenerMessage::new(ListenerEvent::Due, &self.scheduled_set)
+            }).await?;
+        }
+        Ok(())
+    }
+
+}
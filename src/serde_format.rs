@@ -1,5 +1,6 @@
 use {
     crate::*,
+    log::*,
     serde::de::DeserializeOwned,
     std::{
         fs,
@@ -0,0 +1,144 @@
+use {
+    crate::*,
+    log::*,
+    std::{
+        sync::{Arc, RwLock},
+        time::Duration,
+    },
+};
+
+/// The resc instances sharing the watchers of a configuration.
+///
+/// Every instance records itself as a member of a sorted set, scored
+/// by the expiration of its membership, and reads the other live
+/// members. Each watcher is assigned to one of them by rendezvous
+/// hashing, so that only the watchers of a leaving or joining
+/// instance move.
+pub struct Membership {
+    backend: Arc<dyn QueueBackend>,
+    key: String,
+    instance: String,
+    ttl: Duration,
+    /// the live members, as last read
+    members: RwLock<Vec<String>>,
+}
+
+impl Membership {
+    pub fn new(lock_conf: &LockConf, backend: &Arc<dyn QueueBackend>) -> Self {
+        Self {
+            backend: Arc::clone(backend),
+            key: lock_conf.members_key.clone(),
+            instance: lock_conf.instance.clone(),
+            ttl: lock_conf.ttl,
+            members: RwLock::new(Vec::new()),
+        }
+    }
+
+    /// renew the membership of this instance and read the live members
+    pub async fn refresh(&self) -> Result<(), RescError> {
+        let now = precise_now_secs();
+        self.backend.add_to_set(&self.key, &self.instance, now + self.ttl.as_secs_f64()).await?;
+        let mut members = self.backend.live_members(&self.key, now).await?;
+        members.sort();
+        let mut current = self.members.write().unwrap();
+        if *current != members {
+            info!("resc instances: {:?}", &members);
+            *current = members;
+        }
+        Ok(())
+    }
+
+    /// keep the membership alive, forever
+    pub async fn run(&self) {
+        loop {
+            tokio::time::sleep(self.ttl / 3).await;
+            if let Err(e) = self.refresh().await {
+                warn!("can't refresh membership in {:?} : {}", &self.key, e);
+            }
+        }
+    }
+
+    pub fn members(&self) -> Vec<String> {
+        self.members.read().unwrap().clone()
+    }
+
+    /// whether the watcher of this input queue is
+    /// assigned to this instance
+    pub fn is_assigned(&self, input_queue: &str) -> bool {
+        let members = self.members.read().unwrap();
+        rendezvous_owner(&members, input_queue) == Some(self.instance.as_str())
+    }
+}
+
+/// the member a key is assigned to: the one with the highest
+/// hash of the member and the key
+pub fn rendezvous_owner<'m>(members: &'m [String], key: &str) -> Option<&'m str> {
+    members.iter()
+        .max_by_key(|member| fnv1a(&[member.as_bytes(), b"\0", key.as_bytes()]))
+        .map(|member| member.as_str())
+}
+
+/// the 64 bits FNV-1a hash, which, unlike the hash of the std,
+/// is the same for all instances, whatever their version.
+///
+/// It's followed by the finalizer of MurmurHash3, as FNV alone
+/// doesn't mix enough strings differing only by their start.
+fn fnv1a(parts: &[&[u8]]) -> u64 {
+    let mut hash: u64 = 0xcbf29ce484222325;
+    for byte in parts.iter().flat_map(|part| part.iter()) {
+        hash ^= *byte as u64;
+        hash = hash.wrapping_mul(0x100000001b3);
+    }
+    hash ^= hash >> 33;
+    hash = hash.wrapping_mul(0xff51afd7ed558ccd);
+    hash ^= hash >> 33;
+    hash = hash.wrapping_mul(0xc4ceb9fe1a85ec53);
+    hash ^= hash >> 33;
+    hash
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn members(n: usize) -> Vec<String> {
+        (0..n).map(|i| format!("instance-{}", i)).collect()
+    }
+
+    #[test]
+    fn no_owner_without_member() {
+        assert_eq!(rendezvous_owner(&[], "events"), None);
+    }
+
+    #[test]
+    fn owner_doesnt_depend_on_member_order() {
+        let members = members(5);
+        let mut reversed = members.clone();
+        reversed.reverse();
+        for i in 0..50 {
+            let key = format!("events/{}", i);
+            assert_eq!(rendezvous_owner(&members, &key), rendezvous_owner(&reversed, &key));
+        }
+    }
+
+    #[test]
+    fn keys_are_spread_and_moved_only_from_leaving_member() {
+        let members = members(4);
+        let keys: Vec<String> = (0..200).map(|i| format!("events/{}", i)).collect();
+        let owners: Vec<&str> = keys.iter()
+            .map(|key| rendezvous_owner(&members, key).unwrap())
+            .collect();
+        for member in &members {
+            let owned = owners.iter().filter(|&&owner| owner == member).count();
+            assert!(owned > 20, "{} owns only {} keys", member, owned);
+        }
+        // when a member leaves, only its keys change of owner
+        let remaining = members[1..].to_vec();
+        for (key, owner) in keys.iter().zip(&owners) {
+            let new_owner = rendezvous_owner(&remaining, key).unwrap();
+            if *owner != members[0] {
+                assert_eq!(new_owner, *owner);
+            }
+        }
+    }
+}
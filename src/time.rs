@@ -0,0 +1,122 @@
+use {
+    crate::*,
+    serde::{Deserialize, Deserializer},
+    std::time::{Duration, SystemTime},
+};
+
+/// build the Epoch related timestamp, in seconds as f64
+/// because we want to use in in JSON and JS. Precision
+/// in f64 is not lost because this number is smaller than 2^51.
+pub fn now_secs() -> f64 {
+    SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .unwrap()
+        .as_secs()
+        as f64
+}
+
+/// the Epoch related timestamp, in seconds, with the sub-second
+/// precision leases need
+pub fn precise_now_secs() -> f64 {
+    SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .unwrap()
+        .as_secs_f64()
+}
+
+/// parse a time given either as a number of seconds since
+/// the Epoch or as a RFC 3339 date ("2021-02-08T10:30:00Z")
+pub fn parse_time(s: &str) -> Result<f64, RescError> {
+    let s = s.trim();
+    if let Ok(secs) = s.parse::<f64>() {
+        if !secs.is_finite() {
+            return Err(RescError::InvalidTime(s.to_string()));
+        }
+        return Ok(secs);
+    }
+    chrono::DateTime::parse_from_rfc3339(s)
+        .map(|date| date.timestamp() as f64)
+        .map_err(|_| RescError::InvalidTime(s.to_string()))
+}
+
+/// parse a duration given as a number followed by a unit
+/// ("ms", "s", "m", "h" or "d"), for example "30s" or "10m".
+/// A number without unit is a number of seconds.
+pub fn parse_duration(s: &str) -> Result<Duration, ConfError> {
+    let s = s.trim();
+    let unit_start = s
+        .find(|c: char| !c.is_ascii_digit() && c != '.')
+        .unwrap_or(s.len());
+    let (number, unit) = s.split_at(unit_start);
+    let number: f64 = number
+        .parse()
+        .map_err(|_| ConfError::InvalidDuration(s.to_string()))?;
+    let secs = match unit.trim() {
+        "ms" => number / 1000.0,
+        "" | "s" => number,
+        "m" => number * 60.0,
+        "h" => number * 3600.0,
+        "d" => number * 86400.0,
+        _ => {
+            return Err(ConfError::InvalidDuration(s.to_string()));
+        }
+    };
+    Duration::try_from_secs_f64(secs)
+        .map_err(|_| ConfError::InvalidDuration(s.to_string()))
+}
+
+/// deserialize a duration written like "30s"
+/// (to be used with `deserialize_with`)
+pub fn deserialize_duration<'de, D>(deserializer: D) -> Result<Duration, D::Error>
+    where D: Deserializer<'de>
+{
+    let s = String::deserialize(deserializer)?;
+    parse_duration(&s).map_err(serde::de::Error::custom)
+}
+
+/// deserialize an optional duration written like "30s"
+/// (to be used with `deserialize_with`)
+pub fn deserialize_option_duration<'de, D>(deserializer: D) -> Result<Option<Duration>, D::Error>
+    where D: Deserializer<'de>
+{
+    let s: Option<String> = Option::deserialize(deserializer)?;
+    s.map(|s| parse_duration(&s))
+        .transpose()
+        .map_err(serde::de::Error::custom)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn durations() {
+        assert_eq!(parse_duration("30s").unwrap(), Duration::from_secs(30));
+        assert_eq!(parse_duration("45").unwrap(), Duration::from_secs(45));
+        assert_eq!(parse_duration("1.5m").unwrap(), Duration::from_secs(90));
+        assert_eq!(parse_duration("250ms").unwrap(), Duration::from_millis(250));
+        assert_eq!(parse_duration(" 2d ").unwrap(), Duration::from_secs(2 * 86400));
+    }
+
+    #[test]
+    fn invalid_durations() {
+        for s in ["", "s", "10y", "1e3s", "1..2s", "99999999999999999999999d"] {
+            assert!(parse_duration(s).is_err(), "{:?} should be refused", s);
+        }
+    }
+
+    #[test]
+    fn times() {
+        assert_eq!(parse_time("1700000000").unwrap(), 1700000000.0);
+        assert_eq!(parse_time("1700000000.5").unwrap(), 1700000000.5);
+        assert_eq!(parse_time("2021-02-08T10:30:00Z").unwrap(), 1612780200.0);
+        assert_eq!(parse_time("2021-02-08T11:30:00+01:00").unwrap(), 1612780200.0);
+    }
+
+    #[test]
+    fn invalid_times() {
+        for s in ["", "tomorrow", "NaN", "inf", "-infinity", "2021-02-30T10:30:00Z"] {
+            assert!(parse_time(s).is_err(), "{:?} should be refused", s);
+        }
+    }
+}
@@ -1,28 +1,155 @@
 use {
     crate::*,
     log::*,
-    redis::{self, Commands, Connection},
     serde::Deserialize,
     std::{
-        time::SystemTime,
+        sync::{
+            atomic::{AtomicBool, Ordering},
+            Arc, Mutex, RwLock,
+        },
+        time::{Duration, Instant},
+    },
+    tokio::{
+        sync::{watch, Semaphore},
+        task::{JoinError, JoinHandle, JoinSet},
     },
 };
 
-#[derive(Debug, Deserialize)]
+/// time to wait before trying to reconnect to Redis
+const RECONNECT_DELAY: Duration = Duration::from_secs(1);
+
+/// max time, in seconds, of a wait for an event, after which
+/// the watcher checks whether it's been asked to pause or stop
+const TAKE_TIMEOUT: f64 = 1.0;
+
+#[derive(Debug, Clone, Deserialize)]
 pub struct WatcherConf {
     pub input_queue: String,
+    /// the queue where events stay while they're handled, or
+    /// the lease set in lease mode
     pub taken_queue: Option<String>,
+    /// max number of events handled at the same time, overriding
+    /// the global `concurrency` setting
+    pub concurrency: Option<usize>,
+    /// when set, events are leased for this duration instead of
+    /// being moved to a taken queue
+    #[serde(default, deserialize_with = "deserialize_option_duration")]
+    pub lease: Option<Duration>,
+    /// when set, the input queue is split in this number of
+    /// partitions, `<input_queue>/0` to `<input_queue>/<n-1>`,
+    /// each one with its own watcher
+    pub partitions: Option<usize>,
     pub rules: Vec<Rule>,
 }
 
+impl WatcherConf {
+    /// check every rule makes tasks, and the debounced rules can
+    /// be told apart, as their state is keyed by their name
+    pub fn validate(&self) -> Result<(), ConfError> {
+        if self.partitions == Some(0) {
+            return Err(ConfError::NoPartition(self.input_queue.clone()));
+        }
+        if let Some(rule) = self.rules.iter().find(|rule| rule.makers.as_slice().is_empty()) {
+            return Err(ConfError::NoMaker(rule.name.clone()));
+        }
+        let debounced: Vec<&Rule> = self.rules.iter()
+            .filter(|rule| rule.debounce.is_some())
+            .collect();
+        for (i, rule) in debounced.iter().enumerate() {
+            if debounced[..i].iter().any(|r| r.name == rule.name) {
+                return Err(ConfError::DuplicateDebouncedRule(rule.name.clone()));
+            }
+        }
+        Ok(())
+    }
+    /// the queue where events stay while they're handled,
+    /// or the lease set in lease mode
+    pub fn effective_taken_queue(&self) -> String {
+        match (self.taken_queue.as_ref(), self.lease) {
+            (Some(queue), _) => queue.clone(),
+            (None, Some(_)) => format!("{}/leases", &self.input_queue),
+            (None, None) => format!("{}/taken", &self.input_queue),
+        }
+    }
+    /// check the keys used together by scripts are in
+    /// the same slot of a Redis Cluster.
+    ///
+    /// The queues and sets of the tasks which may be delayed must be
+    /// in the slot of the scheduled set, so their hash tag can't
+    /// depend on the event.
+    pub fn check_cluster_slots(&self, scheduled_set: &str) -> Result<(), ConfError> {
+        check_same_slot(&[&self.input_queue, &self.effective_taken_queue()])?;
+        for rule in self.rules.iter().filter(|rule| rule.debounce.is_some()) {
+            check_same_slot(&DebounceKeys::new(&self.input_queue, rule).all())?;
+        }
+        let delaying_makers = self.rules.iter()
+            .flat_map(|rule| rule.makers.as_slice())
+            .filter(|maker| maker.may_delay());
+        for maker in delaying_makers {
+            for pattern in std::iter::once(&maker.queue).chain(&maker.set) {
+                check_same_slot(&[scheduled_set, &pattern.src])?;
+            }
+        }
+        Ok(())
+    }
+    /// the configurations of the watchers of the partitions,
+    /// or just this one when the input queue isn't partitioned
+    /// (or has no partition, which `validate` refuses)
+    pub fn partitioned(self) -> Vec<WatcherConf> {
+        let Some(partitions) = self.partitions.filter(|&n| n > 0) else {
+            return vec![self];
+        };
+        (0..partitions)
+            .map(|i| WatcherConf {
+                input_queue: format!("{}/{}", &self.input_queue, i),
+                taken_queue: self.taken_queue.as_ref().map(|queue| format!("{}/{}", queue, i)),
+                partitions: None,
+                ..self.clone()
+            })
+            .collect()
+    }
+}
+
+/// the firing of the debounced events, done while
+/// the watcher watches its input queue
+#[derive(Default)]
+struct Debouncing {
+    watching: bool,
+    handle: Option<JoinHandle<()>>,
+}
+
 /// A watcher watches the events incoming in one specific queue
 /// and applies rules to generate tasks
+///
+/// In lease mode, the taken events are kept in a lease set, which
+/// several watchers, in several resc instances, can share. An event
+/// whose lease expires, because its watcher died, is requeued.
+/// Identical events handled at the same time share their lease.
+///
+/// Otherwise, when locks are configured, the watcher only runs
+/// while its instance owns the lock of the input queue. With
+/// sharding, it only tries to own it when the input queue is
+/// assigned to its instance.
 pub struct Watcher {
-    con: Connection,
-    listener_channel: String,
+    backend: Arc<dyn QueueBackend>, // shared with the other watchers
+    dispatcher: Dispatcher,
     input_queue: String,
-    taken_queue: String, // can't be shared between watchers
-    ruleset: Ruleset,
+    taken_queue: String, // can't be shared between watchers, unless leased
+    concurrency: usize,
+    lease: Option<Duration>,
+    /// the reaper of the expired leases, in lease mode
+    reaper: Option<Reaper>,
+    lock: Option<WatcherLock>,
+    /// whether this instance owns the lock, when there's one
+    owner: AtomicBool,
+    /// the instances sharing the watchers, when sharding
+    membership: Option<Arc<Membership>>,
+    /// replaced on configuration reload, each event being
+    /// handled with the ruleset current when it was taken
+    ruleset: RwLock<Arc<Ruleset>>,
+    debouncing: Mutex<Debouncing>,
+    control: watch::Sender<WatcherControl>,
+    status: Mutex<WatcherStatus>,
 }
 
 impl Watcher {
@@ -30,41 +157,327 @@ impl Watcher {
     pub fn new(
         watcher_conf: &WatcherConf,
         global_conf: &Conf,
-    ) -> Result<Self, RescError> {
-        let listener_channel = global_conf.listener_channel.clone();
+        backend: &Arc<dyn QueueBackend>,
+    ) -> Self {
         let input_queue = watcher_conf.input_queue.clone();
-        let taken_queue = match watcher_conf.taken_queue.as_ref() {
-            Some(queue) => queue.clone(),
-            None => format!("{}/taken", &input_queue),
-        };
+        let taken_queue = watcher_conf.effective_taken_queue();
         let ruleset = Ruleset {
             rules: watcher_conf.rules.clone(),
         };
-        let client = redis::Client::open(&*global_conf.redis.url)?;
-        let con = client.get_connection()?;
-        debug!("got redis connection");
-        Ok(Self {
-            con,
-            listener_channel,
+        let concurrency = watcher_conf.concurrency
+            .unwrap_or(global_conf.concurrency)
+            .max(1);
+        let status = WatcherStatus::new(&input_queue, &taken_queue, concurrency);
+        let dispatcher = Dispatcher::new(global_conf, Arc::clone(backend)).for_watcher(&input_queue);
+        let reaper = watcher_conf.lease.map(|_| {
+            let reaper_conf = ReaperConf {
+                lease: true,
+                ..ReaperConf::new(&taken_queue, &input_queue)
+            };
+            Reaper::with_dispatcher(&reaper_conf, dispatcher.clone(), backend)
+        });
+        let lock = match (&global_conf.locks, watcher_conf.lease) {
+            (Some(lock_conf), None) => Some(WatcherLock::new(lock_conf, &input_queue, backend)),
+            _ => None,
+        };
+        Self {
+            backend: Arc::clone(backend),
+            dispatcher,
             input_queue,
             taken_queue,
-            ruleset,
-        })
+            concurrency,
+            lease: watcher_conf.lease,
+            reaper,
+            lock,
+            owner: AtomicBool::new(false),
+            membership: None,
+            ruleset: RwLock::new(Arc::new(ruleset)),
+            debouncing: Mutex::new(Debouncing::default()),
+            control: watch::Sender::new(WatcherControl::Run),
+            status: Mutex::new(status),
+        }
+    }
+
+    /// share the watcher with the other instances, the
+    /// watcher being run only by the one it's assigned to
+    pub fn with_membership(mut self, membership: Arc<Membership>) -> Self {
+        self.membership = Some(membership);
+        self
+    }
+
+    /// whether this instance is the one which should run
+    /// the watcher, when sharding
+    fn is_assigned(&self) -> bool {
+        self.membership.as_ref()
+            .is_none_or(|membership| membership.is_assigned(&self.input_queue))
+    }
+
+    pub fn input_queue(&self) -> &str {
+        &self.input_queue
+    }
+
+    pub fn taken_queue(&self) -> &str {
+        &self.taken_queue
+    }
+
+    pub fn concurrency(&self) -> usize {
+        self.concurrency
+    }
+
+    pub fn lease(&self) -> Option<Duration> {
+        self.lease
+    }
+
+    pub fn rules(&self) -> Vec<Rule> {
+        self.ruleset.read().unwrap().rules.clone()
+    }
+
+    /// replace the rules of the watcher. The events already
+    /// being handled finish with the old rules
+    pub fn swap_rules(&self, rules: Vec<Rule>) {
+        info!("watcher on {:?} gets new rules", &self.input_queue);
+        *self.ruleset.write().unwrap() = Arc::new(Ruleset { rules });
+        let mut debouncing = self.debouncing.lock().unwrap();
+        // the debounced events are only fired by the instance
+        // watching the input queue
+        if debouncing.watching && self.owns_input_queue() {
+            self.spawn_debouncer(&mut debouncing);
+        }
+    }
+
+    /// start firing the debounced events, when the
+    /// watching of the input queue starts
+    fn start_debouncing(&self) {
+        let mut debouncing = self.debouncing.lock().unwrap();
+        debouncing.watching = true;
+        self.spawn_debouncer(&mut debouncing);
     }
 
-    pub fn run(&mut self) -> Result<(), RescError> {
-        self.empty_taken_queue();
-        self.watch_input_queue()
+    /// stop firing the debounced events: the ones being
+    /// fired are kept in Redis and fired again on next start
+    fn stop_debouncing(&self) {
+        let mut debouncing = self.debouncing.lock().unwrap();
+        debouncing.watching = false;
+        if let Some(handle) = debouncing.handle.take() {
+            handle.abort();
+        }
+    }
+
+    /// (re)start the task firing the debounced events
+    /// of the current rules
+    fn spawn_debouncer(&self, debouncing: &mut Debouncing) {
+        let mut debouncer = Debouncer::new(
+            &self.input_queue,
+            &self.taken_queue,
+            &self.ruleset.read().unwrap(),
+            &self.dispatcher,
+            &self.backend,
+        );
+        let handle = if debouncer.is_empty() {
+            None
+        } else {
+            Some(tokio::spawn(async move {
+                debouncer.run().await;
+            }))
+        };
+        let old = std::mem::replace(&mut debouncing.handle, handle);
+        if let Some(old) = old {
+            // the debounced events being fired are kept in the
+            // backend and fired again by the new debouncer
+            old.abort();
+        }
+    }
+
+    pub fn status(&self) -> WatcherStatus {
+        self.status.lock().unwrap().clone()
+    }
+
+    /// ask the watcher to run, pause, drain or stop. The
+    /// order is applied between two events
+    pub fn control(&self, control: WatcherControl) {
+        info!("watcher on {:?} asked to {:?}", &self.input_queue, control);
+        self.control.send_replace(control);
+    }
+
+    fn set_state(&self, state: WatcherState) {
+        let mut status = self.status.lock().unwrap();
+        status.state = state;
+        status.refresh_state();
+    }
+
+    fn set_error(&self, error: String) {
+        self.status.lock().unwrap().last_error = Some(error);
+    }
+
+    /// run the watcher until asked to stop.
+    ///
+    /// After a connection loss, the watcher is started again, which
+    /// moves the events whose handling failed back to the input queue
+    pub async fn run(self: Arc<Self>) -> Result<(), RescError> {
+        let watched = loop {
+            let watched = match self.lock.clone() {
+                Some(lock) => Arc::clone(&self).run_when_owner(lock).await,
+                None => Arc::clone(&self).run_owned().await,
+            };
+            match watched {
+                Err(e) if e.is_connection_loss() && *self.control.borrow() != WatcherControl::Stop => {
+                    error!("watcher on {:?} lost its connection : {}", &self.input_queue, e);
+                    self.set_error(e.to_string());
+                    self.set_state(WatcherState::Reconnecting);
+                    tokio::time::sleep(RECONNECT_DELAY).await;
+                    info!("watcher on {:?} restarting", &self.input_queue);
+                    METRICS.redis_reconnects.inc(&[&self.input_queue]);
+                }
+                watched => break watched,
+            }
+        };
+        if let Err(e) = &watched {
+            self.set_error(e.to_string());
+        }
+        self.set_state(WatcherState::Stopped);
+        watched
+    }
+
+    /// run the watcher while this instance owns the lock of the
+    /// input queue, waiting for the lock when it's owned by another
+    /// instance, until asked to stop
+    async fn run_when_owner(self: Arc<Self>, lock: WatcherLock) -> Result<(), RescError> {
+        let mut control = self.control.subscribe();
+        let mut acquired;
+        loop {
+            self.set_state(WatcherState::Standby);
+            loop {
+                if *control.borrow_and_update() == WatcherControl::Stop {
+                    return Ok(());
+                }
+                if self.is_assigned() {
+                    acquired = Instant::now();
+                    match lock.acquire().await {
+                        Ok(true) => break,
+                        Ok(false) => {}
+                        Err(e) => warn!("can't take lock {:?} : {}", lock.key(), e),
+                    }
+                }
+                tokio::select! {
+                    _ = control.changed() => {}
+                    _ = tokio::time::sleep(lock.ttl() / 3) => {}
+                }
+            }
+            info!("this instance now owns the watcher on {:?}", &self.input_queue);
+            self.owner.store(true, Ordering::SeqCst);
+            self.set_state(WatcherState::Idle);
+            let watched = tokio::select! {
+                watched = Arc::clone(&self).run_owned() => watched,
+                // dropping the watching aborts the events being handled,
+                // which stay in the taken queue for the next owner
+                _ = self.renew_lock(&lock, acquired) => Ok(()),
+            };
+            self.abort_watching();
+            // the lock is only deleted if it's still owned by this
+            // instance, which is also the case when the watcher was
+            // assigned to another instance
+            self.owner.store(false, Ordering::SeqCst);
+            if let Err(e) = lock.release().await {
+                warn!("can't release lock {:?} : {}", lock.key(), e);
+            }
+            watched?;
+            if *self.control.borrow() == WatcherControl::Stop {
+                return Ok(());
+            }
+            warn!("this instance lost the watcher on {:?}", &self.input_queue);
+        }
+    }
+
+    /// renew the lock, acquired at `acquired`, every third of its
+    /// ttl, until it's lost, that is until it's owned by another
+    /// instance or it couldn't be renewed for half its ttl, or
+    /// until the watcher is assigned to another instance
+    ///
+    /// Giving up well before the expiration of the lock leaves
+    /// the time to abort the events being handled before another
+    /// instance takes the lock.
+    async fn renew_lock(&self, lock: &WatcherLock, acquired: Instant) {
+        let mut renewal = LockRenewal::new(lock.ttl(), acquired);
+        loop {
+            tokio::time::sleep(renewal.delay()).await;
+            if !self.is_assigned() {
+                info!("watcher on {:?} assigned to another instance", &self.input_queue);
+                break;
+            }
+            // the ttl of the lock starts before the answer of Redis
+            let attempt = Instant::now();
+            match tokio::time::timeout(renewal.remaining(attempt), lock.acquire()).await {
+                Ok(Ok(true)) => {
+                    renewal.renewed(attempt);
+                    continue;
+                }
+                Ok(Ok(false)) => {
+                    warn!("lock {:?} taken by another instance", lock.key());
+                    break;
+                }
+                Ok(Err(e)) => {
+                    warn!("can't renew lock {:?} : {}", lock.key(), e);
+                }
+                Err(_) => {
+                    warn!("renewal of lock {:?} timed out", lock.key());
+                }
+            }
+            if renewal.failed(Instant::now()) {
+                warn!("lock {:?} given up, as it couldn't be renewed", lock.key());
+                break;
+            }
+        }
+        self.owner.store(false, Ordering::SeqCst);
+    }
+
+    /// clean up after the watching ended or was aborted on the
+    /// loss of the lock: the debounced events being fired are
+    /// fired again by the next owner
+    fn abort_watching(&self) {
+        self.stop_debouncing();
+        self.status.lock().unwrap().current_events.clear();
+    }
+
+    /// whether the watcher may take events, which
+    /// needs the lock when there's one
+    fn owns_input_queue(&self) -> bool {
+        self.lock.is_none() || self.owner.load(Ordering::SeqCst)
+    }
+
+    /// run the watcher until asked to stop or, when
+    /// there's a lock, until the lock is lost
+    async fn run_owned(self: Arc<Self>) -> Result<(), RescError> {
+        // leased events are requeued when their lease expires, while
+        // the taken queue is emptied on start
+        let reaping = match &self.reaper {
+            Some(reaper) => {
+                let mut reaper = reaper.clone();
+                Some(tokio::spawn(async move {
+                    reaper.run().await;
+                }))
+            }
+            None => {
+                self.empty_taken_queue().await;
+                None
+            }
+        };
+        self.start_debouncing();
+        let watched = Arc::clone(&self).watch_input_queue().await;
+        self.stop_debouncing();
+        if let Some(reaping) = reaping {
+            reaping.abort();
+        }
+        watched
     }
 
     /// move tasks from the taken queue to the input queue
     ///
     /// This is done on start to reschedule the tasks that
     /// weren't completely handled.
-    fn empty_taken_queue(&mut self) {
+    async fn empty_taken_queue(&self) {
         debug!("watcher cleans its taken queue");
         let mut n = 0;
-        while let Ok(taken) = self.con.rpoplpush::<_, String>(&self.taken_queue, &self.input_queue) {
+        while let Ok(Some(taken)) = self.backend.move_tail(&self.taken_queue, &self.input_queue).await {
             debug!(
                 " moving {:?} from {:?} to {:?}",
                 &taken, &self.taken_queue, &self.input_queue
@@ -72,6 +485,7 @@ impl Watcher {
             n += 1;
         }
         if n > 0 {
+            METRICS.taken_queue_recoveries.add(&[&self.input_queue], n);
             warn!(
                 "moved {} tasks from  {:?} to {:?}",
                 n, &self.taken_queue, &self.input_queue
@@ -80,79 +494,279 @@ impl Watcher {
     }
 
     /// completely handle one event received on the input queue
-    fn handle_input_event(&mut self, event: String) -> Result<(), RescError> {
+    async fn handle_input_event(&self, event: String) -> Result<(), RescError> {
+        let mut dispatcher = self.dispatcher.clone();
+        let handling = self.apply_rules(&mut dispatcher, &event);
+        let handled = match self.lease {
+            Some(lease) => {
+                with_lease(self.backend.as_ref(), &self.taken_queue, &event, lease, handling).await
+            }
+            None => handling.await,
+        };
+        if let Err(e) = &handled {
+            // the listeners may not be reachable, as the failure is
+            // most often a Redis one
+            let _ = dispatcher.notify(ListenerMessage {
+                event: Some(event.clone()),
+                error: Some(e.to_string()),
+                ..ListenerMessage::new(ListenerEvent::Failed, &self.taken_queue)
+            }).await;
+        }
+        handled
+    }
+
+    async fn apply_rules(&self, dispatcher: &mut Dispatcher, event: &str) -> Result<(), RescError> {
         let now = now_secs();
         info!(
             "<- got {:?} in queue {:?} @ {}",
-            &event, &self.input_queue, now
+            event, &self.input_queue, now
         );
+        dispatcher.notify(ListenerMessage {
+            event: Some(event.to_owned()),
+            queue: Some(self.input_queue.clone()),
+            ..ListenerMessage::new(ListenerEvent::Taken, &self.taken_queue)
+        }).await?;
+        let message = |kind, rule: &Rule| ListenerMessage {
+            event: Some(event.to_owned()),
+            rule: Some(rule.name.clone()),
+            ..ListenerMessage::new(kind, &self.taken_queue)
+        };
 
         // we first compute all the rule results
+        let ruleset = Arc::clone(&self.ruleset.read().unwrap());
         let mut results = Vec::new();
-        for rule in self.ruleset.matching_rules(&event) {
+        // the joins completed by the event, to forget once dispatched
+        let mut completed_joins = Vec::new();
+        for rule in ruleset.matching_rules(event) {
+            if let Some(join) = rule.join.as_ref() {
+                if !join.arrive(&*self.backend, rule, event).await? {
+                    continue;
+                }
+            }
+            if rule.debounce.is_some() {
+                Debouncer::record(&*self.backend, &self.input_queue, rule, event).await?;
+                completed_joins.extend(rule.join.as_ref().map(|join| (rule, join)));
+                continue;
+            }
             debug!(" applying rule {:?}", rule.name);
-            match rule.results(&event) {
-                Ok(mut rule_results) => {
-                    results.append(&mut rule_results);
+            METRICS.rule_matches.inc(&[&rule.name]);
+            dispatcher.notify(message(ListenerEvent::RuleMatched, rule)).await?;
+            let mut fetches = Vec::new();
+            let rule_results = rule.results_with_fetches(event, &mut fetches).await;
+            for fetched in fetches {
+                dispatcher.notify(ListenerMessage {
+                    url: Some(fetched.url),
+                    count: Some(fetched.count),
+                    ..message(ListenerEvent::Fetched, rule)
+                }).await?;
+            }
+            match rule_results {
+                Ok(rule_results) => {
+                    results.extend(rule_results.into_iter().map(|r| (rule, r)));
+                    completed_joins.extend(rule.join.as_ref().map(|join| (rule, join)));
                 }
                 Err(e) => {
                     // A possible failure reason is a fetch not possible because of
                     // network or server condition.
                     // TODO should we do something better ? Requeue ?
                     error!("  Rule execution failed: {:?}", e);
+                    self.set_error(format!("rule {:?} failed: {}", &rule.name, e));
+                    if let Some(join) = rule.join.as_ref() {
+                        join.release(&*self.backend, rule, event).await?;
+                    }
+                    dispatcher.notify(ListenerMessage {
+                        error: Some(e.to_string()),
+                        ..message(ListenerEvent::Failed, rule)
+                    }).await?;
                 }
             }
         }
         debug!(" {} result(s)", results.len());
 
         // we now apply the rule results, that is we push the tasks
-        for r in results {
-            // if the rule specifies a task_set, we check the task isn't
-            // already present in the set
-            let in_set_time: Option<i32> = r.set.as_ref()
-                .and_then(|s| self.con.zscore(s, &r.task).ok());
-            if let Some(time) = in_set_time {
-                info!("  task {:?} already queued @ {}", &r.task, time);
-                continue;
-            }
-            info!("  ->  {:?} pushed to queue {:?}", &r.task, &r.queue);
-            if let Some(task_set) = r.set.as_ref() {
-                // we push first to the task set, to avoid a race condition:
-                // a worker not finding the task in the set
-                self.con.zadd(task_set, &r.task, now)?;
-                debug!(
-                    "      {:?} pushed to task_set {:?} @ {}",
-                    &r.task, task_set, now
-                );
-            }
-            self.con.lpush(&r.queue, &r.task)?;
-            self.con.publish(
-                &self.listener_channel,
-                format!("{} TRIGGER {} -> {}", &self.taken_queue, &event, &r.task),
-            )?;
+        for (rule, r) in results {
+            dispatcher.dispatch(&r, &self.taken_queue, event, Some(&rule.name)).await?;
+        }
+        for (rule, join) in completed_joins {
+            join.complete(&*self.backend, rule, event).await?;
         }
 
         // the event can now be removed from the taken queue
-        self.con.lrem(&self.taken_queue, 1, &event)?;
-        self.con.publish(
-            &self.listener_channel,
-            format!("{} DONE {}", &self.taken_queue, &event),
-        )?;
-        debug!(" done with task {:?}", &event);
+        if self.lease.is_some() {
+            self.backend.remove_from_set(&self.taken_queue, event).await?;
+        } else {
+            self.backend.remove(&self.taken_queue, event).await?;
+        }
+        dispatcher.notify(ListenerMessage {
+            event: Some(event.to_owned()),
+            ..ListenerMessage::new(ListenerEvent::Done, &self.taken_queue)
+        }).await?;
+        debug!(" done with task {:?}", event);
         Ok(())
     }
 
     /// continuously watch the input queue an apply rules on the events
-    /// it takes in the queue
-    fn watch_input_queue(&mut self) -> Result<(), RescError> {
+    /// it takes in the queue, until asked to stop or, when there's
+    /// a lock, until the lock is lost
+    ///
+    /// At most `concurrency` events are handled at the same time.
+    /// Every event stays in the taken queue until it's completely
+    /// handled, so that a crash at any point can be recovered
+    /// on next start by `empty_taken_queue`, or, in lease mode,
+    /// when the lease expires.
+    async fn watch_input_queue(self: Arc<Self>) -> Result<(), RescError> {
         info!("watcher launched on queue {:?}...", &self.input_queue);
+        // taking may block, so every watcher has its own taker
+        let mut taker = self.backend.taker().await?;
+        let semaphore = Arc::new(Semaphore::new(self.concurrency));
+        let mut handlings = JoinSet::new();
+        let mut control = self.control.subscribe();
         loop {
-            match self.con.brpoplpush(&self.input_queue, &self.taken_queue, 0) {
-                Ok(event) => {
-                    self.handle_input_event(event)?
+            while let Some(handled) = handlings.try_join_next() {
+                self.check_handled(handled, &mut handlings).await?;
+            }
+            if !self.owns_input_queue() {
+                // another instance may soon empty the taken queue: the
+                // events being handled are aborted, when the handlings
+                // are dropped, and left there for it
+                return Ok(());
+            }
+            let order = *control.borrow_and_update();
+            match order {
+                WatcherControl::Run => {
+                    self.set_state(WatcherState::Idle);
+                }
+                WatcherControl::Pause => {
+                    self.set_state(WatcherState::Paused);
+                    tokio::select! {
+                        _ = control.changed() => {}
+                        Some(handled) = handlings.join_next() => {
+                            self.check_handled(handled, &mut handlings).await?;
+                        }
+                    }
+                    continue;
+                }
+                WatcherControl::Drain if handlings.is_empty() => {
+                    if self.status().state != WatcherState::Drained {
+                        info!("watcher on {:?} drained", &self.input_queue);
+                        self.set_state(WatcherState::Drained);
+                    }
+                    // the ownership of the input queue is checked again on timeout
+                    let _ = tokio::time::timeout(
+                        Duration::from_secs_f64(TAKE_TIMEOUT),
+                        control.changed(),
+                    ).await;
+                    continue;
+                }
+                WatcherControl::Drain => {
+                    self.set_state(WatcherState::Draining);
+                    tokio::select! {
+                        _ = control.changed() => {}
+                        Some(handled) = handlings.join_next() => {
+                            self.check_handled(handled, &mut handlings).await?;
+                        }
+                    }
+                    continue;
+                }
+                WatcherControl::Stop => {
+                    while let Some(handled) = handlings.join_next().await {
+                        self.check_handled(handled, &mut handlings).await?;
+                    }
+                    info!("watcher on {:?} stopped", &self.input_queue);
+                    return Ok(());
+                }
+            }
+            let permit = Arc::clone(&semaphore).acquire_owned().await
+                .expect("watcher semaphore closed");
+            let taken = match self.lease {
+                Some(lease) => {
+                    self.backend
+                        .take_leased(&self.input_queue, &self.taken_queue, lease, TAKE_TIMEOUT)
+                        .await
+                }
+                None => {
+                    taker
+                        .take(&self.input_queue, &self.taken_queue, TAKE_TIMEOUT)
+                        .await
+                }
+            };
+            match taken {
+                Ok(Some(event)) => {
+                    METRICS.events_taken.inc(&[&self.input_queue]);
+                    {
+                        let mut status = self.status.lock().unwrap();
+                        status.current_events.push(event.clone());
+                        status.refresh_state();
+                    }
+                    let watcher = Arc::clone(&self);
+                    handlings.spawn(async move {
+                        let handled = watcher.handle_input_event(event.clone()).await;
+                        {
+                            let mut status = watcher.status.lock().unwrap();
+                            if let Some(idx) = status.current_events.iter().position(|e| e == &event) {
+                                status.current_events.remove(idx);
+                            }
+                            status.refresh_state();
+                        }
+                        drop(permit);
+                        handled
+                    });
+                }
+                Ok(None) => {} // timeout, no event
+                Err(e) => {
+                    error!("taking from {:?} failed : {}", &self.input_queue, e);
+                    self.set_error(format!("taking failed: {}", e));
+                    if e.is_connection_loss() {
+                        self.set_state(WatcherState::Reconnecting);
+                        if let Some(new_taker) = self.reconnect().await {
+                            taker = new_taker;
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    /// check the outcome of the handling of an event.
+    ///
+    /// On failure, the failed event is left in the taken queue. We stop
+    /// taking new events but let the other handlings finish so that their
+    /// events aren't replayed on restart
+    async fn check_handled(
+        &self,
+        handled: Result<Result<(), RescError>, JoinError>,
+        handlings: &mut JoinSet<Result<(), RescError>>,
+    ) -> Result<(), RescError> {
+        match handled {
+            Ok(Ok(())) => Ok(()),
+            Ok(Err(e)) => {
+                while handlings.join_next().await.is_some() {}
+                Err(e)
+            }
+            Err(e) => {
+                error!("event handling on {:?} panicked: {}", &self.input_queue, e);
+                self.set_error(format!("event handling panicked: {}", e));
+                Ok(())
+            }
+        }
+    }
+
+    /// make a new taker, retrying until the backend is
+    /// available again or the watcher is asked to stop
+    async fn reconnect(&self) -> Option<Box<dyn EventTaker>> {
+        loop {
+            tokio::time::sleep(RECONNECT_DELAY).await;
+            if *self.control.borrow() == WatcherControl::Stop {
+                return None;
+            }
+            match self.backend.taker().await {
+                Ok(taker) => {
+                    info!("watcher on {:?} reconnected", &self.input_queue);
+                    METRICS.redis_reconnects.inc(&[&self.input_queue]);
+                    return Some(taker);
                 }
                 Err(e) => {
-                    error!("BRPOPLPUSH on {:?} failed : {}", &self.input_queue, e);
+                    warn!("watcher on {:?} can't reconnect : {}", &self.input_queue, e);
                 }
             }
         }
@@ -160,13 +774,184 @@ impl Watcher {
 
 }
 
-/// build the Epoch related timestamp, in seconds as f64
-/// because we want to use in in JSON and JS. Precision
-/// in f64 is not lost because this number is smaller than 2^51.
-fn now_secs() -> f64 {
-    SystemTime::now()
-        .duration_since(SystemTime::UNIX_EPOCH)
-        .unwrap()
-        .as_secs()
-        as f64
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// a configuration with one watcher on "events", whose
+    /// rule is given, and the backend the watcher runs on
+    fn start_watcher(rule: Rule) -> (MemoryBackend, Arc<Watcher>, JoinHandle<Result<(), RescError>>) {
+        let conf = Conf::builder("redis://127.0.0.1/", "listener")
+            .watcher(WatcherConf::new("events").rule(rule))
+            .build()
+            .unwrap();
+        let memory = MemoryBackend::new();
+        let backend: Arc<dyn QueueBackend> = Arc::new(memory.clone());
+        let watcher = Arc::new(Watcher::new(&conf.watchers[0], &conf, &backend));
+        let handle = tokio::spawn(Arc::clone(&watcher).run());
+        (memory, watcher, handle)
+    }
+
+    /// wait until the events are handled, which is when
+    /// they're out of the input and taken queues
+    async fn wait_handled(memory: &MemoryBackend) {
+        for _ in 0..500 {
+            if memory.queue("events").is_empty() && memory.queue("events/taken").is_empty() {
+                return;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        panic!("events not handled");
+    }
+
+    async fn stop(watcher: Arc<Watcher>, handle: JoinHandle<Result<(), RescError>>) {
+        watcher.control(WatcherControl::Stop);
+        handle.await.unwrap().unwrap();
+    }
+
+    #[tokio::test]
+    async fn event_makes_task() {
+        let rule = Rule::builder("trt", r"^acq/(?P<process>\w+)/(?P<product>\w+)$")
+            .make(Maker::new("trt/${process}/${product}", "trt/${process}/todo"))
+            .build()
+            .unwrap();
+        let (memory, watcher, handle) = start_watcher(rule);
+        memory.push("events", "acq/a/1").await.unwrap();
+        memory.push("events", "unrelated").await.unwrap();
+        wait_handled(&memory).await;
+        assert_eq!(memory.queue("trt/a/todo"), vec!["trt/a/1"]);
+        stop(watcher, handle).await;
+        assert_eq!(
+            memory.published("listener"),
+            vec![
+                "events/taken TRIGGER acq/a/1 -> trt/a/1",
+                "events/taken DONE acq/a/1",
+                "events/taken DONE unrelated",
+            ],
+        );
+    }
+
+    #[tokio::test]
+    async fn standby_watcher_doesnt_debounce_on_swap() {
+        let rule = Rule::builder("trt", r"^acq/(?P<process>\w+)$")
+            .make(Maker::new("trt/${process}", "trt/todo"))
+            .build()
+            .unwrap();
+        let conf = Conf::builder("redis://127.0.0.1/", "listener")
+            .watcher(WatcherConf::new("events").rule(rule.clone()))
+            .locks(LockConf::default())
+            .build()
+            .unwrap();
+        let memory = MemoryBackend::new();
+        // the lock is owned by another instance
+        memory.lock("resc/locks/events", "other", Duration::from_secs(60)).await.unwrap();
+        let backend: Arc<dyn QueueBackend> = Arc::new(memory.clone());
+        let watcher = Arc::new(Watcher::new(&conf.watchers[0], &conf, &backend));
+        let handle = tokio::spawn(Arc::clone(&watcher).run());
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        assert_eq!(watcher.status().state, WatcherState::Standby);
+        let debounced = Rule::builder("debounced", r"^acq/(?P<process>\w+)$")
+            .make(Maker::new("trt/${process}", "trt/todo"))
+            .debounce(Duration::from_secs(1), "${process}")
+            .build()
+            .unwrap();
+        watcher.swap_rules(vec![debounced]);
+        assert_eq!(watcher.rules()[0].name, "debounced");
+        assert!(!watcher.debouncing.lock().unwrap().watching);
+        stop(watcher, handle).await;
+    }
+
+    #[tokio::test]
+    async fn running_watcher_debounces_on_swap() {
+        let rule = Rule::builder("trt", r"^acq/(?P<process>\w+)$")
+            .make(Maker::new("trt/${process}", "trt/todo"))
+            .build()
+            .unwrap();
+        let (memory, watcher, handle) = start_watcher(rule.clone());
+        memory.push("events", "acq/a").await.unwrap();
+        wait_handled(&memory).await;
+        assert!(watcher.debouncing.lock().unwrap().watching);
+        watcher.swap_rules(vec![rule]);
+        let watcher_ref = Arc::clone(&watcher);
+        stop(watcher, handle).await;
+        assert!(!watcher_ref.debouncing.lock().unwrap().watching);
+    }
+
+    #[tokio::test]
+    async fn join_applies_once_complete() {
+        let rule = Rule::builder("merge", r"^(?P<step>a|b)/(?P<p>\w+)$")
+            .join(Join::new("resc/join/merge/${p}", &["a/${p}", "b/${p}"]))
+            .make(Maker::new("merge/${p}", "merge/todo"))
+            .build()
+            .unwrap();
+        let (memory, watcher, handle) = start_watcher(rule);
+        memory.push("events", "a/1").await.unwrap();
+        memory.push("events", "b/2").await.unwrap();
+        wait_handled(&memory).await;
+        assert!(memory.queue("merge/todo").is_empty());
+        memory.push("events", "b/1").await.unwrap();
+        memory.push("events", "a/1").await.unwrap();
+        wait_handled(&memory).await;
+        assert_eq!(memory.queue("merge/todo"), vec!["merge/1"]);
+        stop(watcher, handle).await;
+    }
+
+    #[tokio::test]
+    async fn task_in_set_isnt_pushed_again() {
+        let rule = Rule::builder("trt", r"^acq/(?P<process>\w+)/(?P<product>\w+)$")
+            .make(Maker::new("trt/${process}/${product}", "trt/todo").set("trt/set"))
+            .build()
+            .unwrap();
+        let (memory, watcher, handle) = start_watcher(rule);
+        memory.push("events", "acq/a/1").await.unwrap();
+        memory.push("events", "acq/a/1").await.unwrap();
+        memory.push("events", "acq/a/2").await.unwrap();
+        wait_handled(&memory).await;
+        assert_eq!(memory.queue("trt/todo"), vec!["trt/a/2", "trt/a/1"]);
+        let set = memory.set("trt/set");
+        assert_eq!(set.len(), 2);
+        assert!(set.contains_key("trt/a/1"));
+        stop(watcher, handle).await;
+    }
+
+    #[tokio::test]
+    async fn delayed_task_is_scheduled() {
+        let rule = Rule::builder("trt", r"^acq/(?P<process>\w+)$")
+            .make(Maker::new("trt/${process}", "trt/todo").delay(Duration::from_secs(3600)))
+            .build()
+            .unwrap();
+        let (memory, watcher, handle) = start_watcher(rule);
+        let before = now_secs();
+        memory.push("events", "acq/a").await.unwrap();
+        wait_handled(&memory).await;
+        assert!(memory.queue("trt/todo").is_empty());
+        let scheduled = memory.set(&Conf::default_scheduled_set());
+        assert_eq!(scheduled.len(), 1);
+        let (member, due) = scheduled.into_iter().next().unwrap();
+        let task: ScheduledTask = serde_json::from_str(&member).unwrap();
+        assert_eq!(task.task, "trt/a");
+        assert_eq!(task.queue, "trt/todo");
+        assert!(due >= before + 3600.0);
+        stop(watcher, handle).await;
+    }
+
+    #[tokio::test]
+    async fn taken_events_are_handled_again_on_start() {
+        let rule = Rule::builder("trt", r"^acq/(?P<process>\w+)$")
+            .make(Maker::new("trt/${process}", "trt/todo"))
+            .build()
+            .unwrap();
+        let memory = MemoryBackend::new();
+        memory.push("events/taken", "acq/a").await.unwrap();
+        let conf = Conf::builder("redis://127.0.0.1/", "listener")
+            .watcher(WatcherConf::new("events").rule(rule))
+            .build()
+            .unwrap();
+        let backend: Arc<dyn QueueBackend> = Arc::new(memory.clone());
+        let watcher = Arc::new(Watcher::new(&conf.watchers[0], &conf, &backend));
+        let handle = tokio::spawn(Arc::clone(&watcher).run());
+        wait_handled(&memory).await;
+        assert_eq!(memory.queue("trt/todo"), vec!["trt/a"]);
+        stop(watcher, handle).await;
+    }
 }
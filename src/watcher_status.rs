@@ -0,0 +1,75 @@
+use {
+    serde::Serialize,
+};
+
+/// What an operator asks a watcher to do
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum WatcherControl {
+    /// take and handle events
+    Run,
+    /// don't take new events (the ones being handled go on)
+    Pause,
+    /// don't take new events, wait for the ones being handled
+    /// to be finished, then stay idle, reported as drained,
+    /// until asked to run or stop
+    Drain,
+    /// finish the events being handled, then stop for good
+    Stop,
+}
+
+/// The phase of the watcher loop
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum WatcherState {
+    /// waiting for events
+    Idle,
+    /// handling at least one event
+    Processing,
+    /// waiting for Redis to be available again
+    Reconnecting,
+    /// waiting for the lock of the input queue, owned
+    /// by another resc instance
+    Standby,
+    Paused,
+    /// paused, with events still being handled
+    Draining,
+    /// paused, with no event being handled
+    Drained,
+    Stopped,
+}
+
+/// What a watcher is doing, as reported to operators
+#[derive(Debug, Clone, Serialize)]
+pub struct WatcherStatus {
+    pub input_queue: String,
+    pub taken_queue: String,
+    pub concurrency: usize,
+    pub state: WatcherState,
+    /// the events being handled
+    pub current_events: Vec<String>,
+    pub last_error: Option<String>,
+}
+
+impl WatcherStatus {
+    pub fn new(input_queue: &str, taken_queue: &str, concurrency: usize) -> Self {
+        Self {
+            input_queue: input_queue.to_owned(),
+            taken_queue: taken_queue.to_owned(),
+            concurrency,
+            state: WatcherState::Idle,
+            current_events: Vec::new(),
+            last_error: None,
+        }
+    }
+    /// update the state after a change of the events being
+    /// handled, unless the watcher isn't running
+    pub fn refresh_state(&mut self) {
+        self.state = match (self.state, self.current_events.is_empty()) {
+            (WatcherState::Idle | WatcherState::Processing, true) => WatcherState::Idle,
+            (WatcherState::Idle | WatcherState::Processing, false) => WatcherState::Processing,
+            (WatcherState::Draining | WatcherState::Drained, true) => WatcherState::Drained,
+            (WatcherState::Draining | WatcherState::Drained, false) => WatcherState::Draining,
+            (state, _) => state,
+        };
+    }
+}
@@ -0,0 +1,305 @@
+use {
+    crate::*,
+    log::*,
+    serde::Deserialize,
+    std::{
+        future::Future,
+        sync::Arc,
+        time::Duration,
+    },
+    tokio::task::JoinSet,
+};
+
+/// time to wait before trying to reconnect after a connection loss
+const WORKER_RECONNECT_DELAY: Duration = Duration::from_secs(1);
+
+/// max time, in seconds, of a wait for a task
+const WORKER_TAKE_TIMEOUT: f64 = 5.0;
+
+/// how long the number of failed attempts of a task is kept
+const ATTEMPTS_TTL: Duration = Duration::from_secs(7 * 24 * 60 * 60);
+
+/// The queues a worker works with
+#[derive(Debug, Clone, Deserialize)]
+pub struct WorkerConf {
+    /// the queue where the tasks to do are
+    pub queue: String,
+    /// the queue where a task stays while it's being done,
+    /// by default the task queue followed by "/taken", or the
+    /// lease set in lease mode, by default the task queue
+    /// followed by "/leases"
+    pub taken_queue: Option<String>,
+    /// the task set, when the task queue is deduplicated
+    pub set: Option<String>,
+    /// the queue where done tasks are notified, usually the
+    /// input queue of a resc watcher
+    pub done_queue: String,
+    /// the queue where failure events are pushed, by default
+    /// the done queue
+    pub failed_queue: Option<String>,
+    /// whether the tasks found in the taken queue on start
+    /// are moved back to the task queue. This is only safe when
+    /// only one worker uses this taken queue
+    #[serde(default)]
+    pub recover_on_start: bool,
+    /// max number of tasks done at the same time
+    #[serde(default = "WorkerConf::default_concurrency")]
+    pub concurrency: usize,
+    /// how long the heartbeat of the worker lasts. It's renewed
+    /// three times per period, so a resc reaper requeues the
+    /// taken tasks at most this long after the worker died
+    #[serde(default = "WorkerConf::default_heartbeat_ttl", deserialize_with = "deserialize_duration")]
+    pub heartbeat_ttl: Duration,
+    /// when set, tasks are leased for this duration instead of
+    /// being moved to a taken queue. The lease is extended while
+    /// the task is being done, and shared with identical tasks
+    #[serde(default, deserialize_with = "deserialize_option_duration")]
+    pub lease: Option<Duration>,
+}
+
+impl WorkerConf {
+    pub fn new(queue: &str, done_queue: &str) -> Self {
+        Self {
+            queue: queue.to_owned(),
+            taken_queue: None,
+            set: None,
+            done_queue: done_queue.to_owned(),
+            failed_queue: None,
+            recover_on_start: false,
+            concurrency: WorkerConf::default_concurrency(),
+            heartbeat_ttl: WorkerConf::default_heartbeat_ttl(),
+            lease: None,
+        }
+    }
+    pub fn default_concurrency() -> usize {
+        1
+    }
+    pub fn default_heartbeat_ttl() -> Duration {
+        Duration::from_secs(30)
+    }
+    pub fn taken_queue(&self) -> String {
+        match (&self.taken_queue, self.lease) {
+            (Some(queue), _) => queue.clone(),
+            (None, Some(_)) => format!("{}/leases", &self.queue),
+            (None, None) => format!("{}/taken", &self.queue),
+        }
+    }
+}
+
+/// A worker takes the tasks of a queue and does them with a
+/// handler, following the protocol resc expects from workers:
+///
+/// 1. the task is atomically moved from the task queue to the taken queue
+/// 2. it's removed from the task set, so that it can be queued again
+///    while it's being done
+/// 3. the handler does the task
+/// 4. on success, the task is pushed to the done queue. On failure,
+///    a failure event with the number of failed attempts, for example
+///    "failed/1/the-task", is pushed to the failed queue
+/// 5. the task is removed from the taken queue
+///
+/// A crash before the last step leaves the task in the taken
+/// queue, from where it can be recovered. While it runs, the worker
+/// keeps a heartbeat key alive, so that a resc reaper can requeue
+/// the taken tasks once the worker is dead.
+///
+/// In lease mode, the task is moved to a lease set instead of the
+/// taken queue, and the lease is extended until the task is done.
+/// A resc reaper requeues the tasks whose lease expired.
+pub struct Worker {
+    conf: WorkerConf,
+    taken_queue: String,
+    backend: Arc<dyn QueueBackend>,
+}
+
+impl Worker {
+
+    pub fn new(conf: WorkerConf, backend: Arc<dyn QueueBackend>) -> Self {
+        let taken_queue = conf.taken_queue();
+        Self {
+            conf,
+            taken_queue,
+            backend,
+        }
+    }
+
+    /// move the tasks of the taken queue back to the task queue,
+    /// returning how many there were
+    pub async fn recover(&self) -> Result<usize, RescError> {
+        let mut n = 0;
+        while let Some(task) = self.backend.move_tail(&self.taken_queue, &self.conf.queue).await? {
+            info!("recovered task {:?}", task);
+            n += 1;
+        }
+        Ok(n)
+    }
+
+    /// renew the heartbeat key of the taken queue, forever
+    async fn beat(&self) {
+        let key = heartbeat_key(&self.taken_queue);
+        let value = std::process::id().to_string();
+        loop {
+            if let Err(e) = self.backend.set_expiring(&key, &value, self.conf.heartbeat_ttl).await {
+                warn!("heartbeat of {:?} failed: {}", &self.taken_queue, e);
+            }
+            tokio::time::sleep(self.conf.heartbeat_ttl / 3).await;
+        }
+    }
+
+    /// do the tasks of the queue, forever, or until an error
+    /// which isn't a connection loss, or a panic of the handler
+    ///
+    /// Up to `concurrency` tasks are done at the same time, each
+    /// one in its own loop, with its own taker.
+    pub async fn run<H, F>(self: Arc<Self>, handler: H) -> Result<(), RescError>
+    where
+        H: Fn(String) -> F + Send + Sync + 'static,
+        F: Future<Output = anyhow::Result<()>> + Send,
+    {
+        let mut loops = JoinSet::new();
+        if self.conf.lease.is_none() {
+            loops.spawn({
+                let worker = Arc::clone(&self);
+                async move {
+                    worker.beat().await;
+                    Ok(())
+                }
+            });
+            if self.conf.recover_on_start {
+                self.recover().await?;
+            }
+        }
+        info!("worker listening on queue {:?}", &self.conf.queue);
+        let handler = Arc::new(handler);
+        for _ in 0..self.conf.concurrency.max(1) {
+            let worker = Arc::clone(&self);
+            let handler = Arc::clone(&handler);
+            loops.spawn(async move {
+                worker.work_loop(handler.as_ref()).await
+            });
+        }
+        // returning drops the other loops, which stops the heartbeat,
+        // so that the task of a panicked loop, left in the taken
+        // queue, is requeued by a reaper
+        while let Some(ended) = loops.join_next().await {
+            match ended {
+                Ok(Err(e)) => return Err(e),
+                Err(e) => {
+                    error!("worker loop on {:?} panicked: {}", &self.conf.queue, e);
+                    return Err(RescError::WorkerPanic(self.conf.queue.clone(), e.to_string()));
+                }
+                Ok(Ok(())) => {}
+            }
+        }
+        Ok(())
+    }
+
+    async fn work_loop<H, F>(&self, handler: &H) -> Result<(), RescError>
+    where
+        H: Fn(String) -> F,
+        F: Future<Output = anyhow::Result<()>>,
+    {
+        let mut taker = self.backend.taker().await?;
+        loop {
+            match self.work_next(taker.as_mut(), handler, WORKER_TAKE_TIMEOUT).await {
+                Ok(_) => {}
+                Err(e) if e.is_connection_loss() => {
+                    warn!("worker on {:?} lost its connection: {}", &self.conf.queue, e);
+                    tokio::time::sleep(WORKER_RECONNECT_DELAY).await;
+                    match self.backend.taker().await {
+                        Ok(new_taker) => taker = new_taker,
+                        Err(e) => warn!("worker on {:?} can't reconnect: {}", &self.conf.queue, e),
+                    }
+                }
+                Err(e) => {
+                    return Err(e);
+                }
+            }
+        }
+    }
+
+    /// wait at most `timeout` seconds for a task and do it,
+    /// returning the task, if there was one
+    pub async fn work_next<H, F>(
+        &self,
+        taker: &mut dyn EventTaker,
+        handler: &H,
+        timeout: f64,
+    ) -> Result<Option<String>, RescError>
+    where
+        H: Fn(String) -> F,
+        F: Future<Output = anyhow::Result<()>>,
+    {
+        let taken = match self.conf.lease {
+            Some(lease) => {
+                self.backend.take_leased(&self.conf.queue, &self.taken_queue, lease, timeout).await?
+            }
+            None => taker.take(&self.conf.queue, &self.taken_queue, timeout).await?,
+        };
+        let Some(task) = taken else {
+            return Ok(None);
+        };
+        debug!("worker took {:?}", &task);
+        if let Some(set) = &self.conf.set {
+            // removed before doing the task, so that it can be
+            // queued again if something changes meanwhile
+            if !self.backend.remove_from_set(set, &task).await? {
+                warn!("task {:?} wasn't in set {:?}", &task, set);
+            }
+        }
+        // the failed attempts are counted per task
+        let attempts_key = format!("{}/attempts/{}", &self.conf.queue, &task);
+        let handling = handler(task.clone());
+        let handled = match self.conf.lease {
+            Some(lease) => {
+                with_lease(self.backend.as_ref(), &self.taken_queue, &task, lease, handling).await
+            }
+            None => handling.await,
+        };
+        match handled {
+            Ok(()) => {
+                debug!("task {:?} done", &task);
+                self.backend.delete(&attempts_key).await?;
+                self.backend.push(&self.conf.done_queue, &task).await?;
+            }
+            Err(e) => {
+                let attempt = self.backend.increment(&attempts_key, ATTEMPTS_TTL).await?;
+                error!("task {:?} failed (attempt {}): {}", &task, attempt, e);
+                let failed_queue = self.conf.failed_queue.as_ref()
+                    .unwrap_or(&self.conf.done_queue);
+                self.backend.push(failed_queue, &failed_event(&task, attempt)).await?;
+            }
+        }
+        if self.conf.lease.is_some() {
+            self.backend.remove_from_set(&self.taken_queue, &task).await?;
+        } else {
+            self.backend.remove(&self.taken_queue, &task).await?;
+        }
+        Ok(Some(task))
+    }
+
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn panicking_handler_stops_the_worker() {
+        let memory = MemoryBackend::new();
+        memory.push("tasks", "boom").await.unwrap();
+        let conf = WorkerConf::new("tasks", "done");
+        let worker = Arc::new(Worker::new(conf, Arc::new(memory.clone())));
+        let run = worker.run(|task| async move {
+            if task == "boom" {
+                panic!("can't do {}", task);
+            }
+            Ok(())
+        });
+        let ran = tokio::time::timeout(Duration::from_secs(5), run).await.unwrap();
+        assert!(matches!(ran, Err(RescError::WorkerPanic(..))));
+        // the task is left for the reaper, once the heartbeat expires
+        assert_eq!(memory.queue("tasks/taken"), vec!["boom"]);
+        assert!(memory.queue("done").is_empty());
+    }
+}
@@ -0,0 +1,298 @@
+use {
+    crate::*,
+    serde::Deserialize,
+    std::time::Duration,
+};
+
+/// A workflow declares the stages of a pipeline and their
+/// dependencies. It's compiled into rules of the watcher of
+/// its input queue.
+///
+/// The tasks of the stages are pushed to their queues, and the
+/// workers notify their completion in the workflow's input
+/// queue. A stage depending on several stages is made of joins.
+#[derive(Debug, Clone, Deserialize)]
+pub struct WorkflowConf {
+
+    pub name: String,
+
+    /// the queue where the events of the workflow arrive
+    /// (root events and task completions)
+    pub input_queue: String,
+
+    pub stages: Vec<StageConf>,
+
+    /// the time after which an incomplete join of a stage
+    /// depending on several stages is forgotten
+    #[serde(default = "Join::default_ttl", deserialize_with = "deserialize_duration")]
+    pub join_ttl: Duration,
+
+}
+
+/// A stage of a workflow.
+///
+/// A stage without `after` is a root: its tasks aren't generated
+/// by resc but come as events in the input queue.
+#[derive(Debug, Clone, Deserialize)]
+pub struct StageConf {
+
+    pub name: String,
+
+    /// the task template. It's used both to generate the task and to
+    /// recognize its completion, so it must be distinct from the
+    /// templates of the other stages
+    pub task: Pattern,
+
+    /// the queue where the tasks are pushed (not used for roots)
+    pub queue: Option<Pattern>,
+
+    /// the optional task set used for deduplicating
+    pub set: Option<Pattern>,
+
+    /// the names of the stages which must be done before this one
+    #[serde(default)]
+    pub after: Vec<String>,
+
+}
+
+impl StageConf {
+    pub fn is_root(&self) -> bool {
+        self.after.is_empty()
+    }
+    /// the variables needed to generate the task, queue and set
+    fn variables(&self) -> Vec<&str> {
+        let mut variables = self.task.variables();
+        variables.extend(self.queue.iter().flat_map(|p| p.variables()));
+        variables.extend(self.set.iter().flat_map(|p| p.variables()));
+        variables.sort_unstable();
+        variables.dedup();
+        variables
+    }
+}
+
+impl WorkflowConf {
+
+    fn stage(&self, name: &str) -> Option<&StageConf> {
+        self.stages.iter().find(|s| s.name == name)
+    }
+
+    fn error(&self, reason: String) -> ConfError {
+        ConfError::InvalidWorkflow(self.name.clone(), reason)
+    }
+
+    /// check the stages are consistent, and that every stage is
+    /// reachable from the roots, without cycle
+    pub fn validate(&self) -> Result<(), ConfError> {
+        for (i, stage) in self.stages.iter().enumerate() {
+            if self.stages[..i].iter().any(|s| s.name == stage.name) {
+                return Err(self.error(format!("several stages are named {:?}", &stage.name)));
+            }
+            if let Err(e) = stage.task.to_regex() {
+                return Err(self.error(format!("invalid task of stage {:?}: {}", &stage.name, e)));
+            }
+            for dep in &stage.after {
+                if self.stage(dep).is_none() {
+                    return Err(self.error(format!(
+                        "stage {:?} depends on unknown stage {:?}", &stage.name, dep,
+                    )));
+                }
+            }
+            if !stage.is_root() && stage.queue.is_none() {
+                return Err(self.error(format!("stage {:?} has no queue", &stage.name)));
+            }
+            // a completion must tell only one stage
+            if let Some(other) = self.stages[..i].iter().find(|s| s.task.overlaps(&stage.task)) {
+                return Err(self.error(format!(
+                    "tasks of stages {:?} and {:?} can't be told apart", &other.name, &stage.name,
+                )));
+            }
+        }
+        if let Some(cycle) = self.find_cycle() {
+            return Err(self.error(format!("cycle {}", cycle.join(" -> "))));
+        }
+        // a stage is reachable when all its dependencies are, and when each
+        // of them gives the variables needed to generate the task
+        let mut reachable: Vec<&str> = self.stages.iter()
+            .filter(|s| s.is_root())
+            .map(|s| s.name.as_str())
+            .collect();
+        if reachable.is_empty() {
+            return Err(self.error("no root stage".to_string()));
+        }
+        loop {
+            let newly_reachable = self.stages.iter()
+                .filter(|s| !reachable.contains(&s.name.as_str()))
+                .find(|s| s.after.iter().all(|dep| reachable.contains(&dep.as_str())));
+            let Some(stage) = newly_reachable else {
+                break;
+            };
+            for dep in &stage.after {
+                let given = self.stage(dep).unwrap().task.variables();
+                if let Some(missing) = stage.variables().into_iter().find(|v| !given.contains(v)) {
+                    return Err(self.error(format!(
+                        "stage {:?} is unreachable: variable {:?} isn't given by stage {:?}",
+                        &stage.name, missing, dep,
+                    )));
+                }
+            }
+            reachable.push(&stage.name);
+        }
+        if let Some(stage) = self.stages.iter().find(|s| !reachable.contains(&s.name.as_str())) {
+            return Err(self.error(format!("stage {:?} is unreachable", &stage.name)));
+        }
+        Ok(())
+    }
+
+    /// return the names of the stages of a dependency cycle, if any
+    fn find_cycle(&self) -> Option<Vec<&str>> {
+        fn visit<'s>(
+            wf: &'s WorkflowConf,
+            stage: &'s StageConf,
+            path: &mut Vec<&'s str>,
+            done: &mut Vec<&'s str>,
+        ) -> Option<Vec<&'s str>> {
+            if let Some(start) = path.iter().position(|&name| name == stage.name) {
+                let mut cycle = path[start..].to_vec();
+                cycle.push(&stage.name);
+                return Some(cycle);
+            }
+            if done.contains(&stage.name.as_str()) {
+                return None;
+            }
+            path.push(&stage.name);
+            for dep in &stage.after {
+                if let Some(dep) = wf.stage(dep) {
+                    if let Some(cycle) = visit(wf, dep, path, done) {
+                        return Some(cycle);
+                    }
+                }
+            }
+            path.pop();
+            done.push(&stage.name);
+            None
+        }
+        let mut done = Vec::new();
+        for stage in &self.stages {
+            let mut path = Vec::new();
+            if let Some(mut cycle) = visit(self, stage, &mut path, &mut done) {
+                // dependencies were followed backwards
+                cycle.reverse();
+                return Some(cycle);
+            }
+        }
+        None
+    }
+
+    /// build the rules generating the tasks of the stages on
+    /// completion of the stages they depend on.
+    ///
+    /// The workflow must have been validated.
+    pub fn compile(&self) -> Vec<Rule> {
+        let mut rules = Vec::new();
+        for stage in self.stages.iter().filter(|s| !s.is_root()) {
+            let maker = Maker {
+                name: Some(stage.name.clone()),
+                task: stage.task.clone(),
+                queue: stage.queue.clone().unwrap(),
+                set: stage.set.clone(),
+                delay: None,
+                at: None,
+                retry: None,
+            };
+            let join_key = stage.variables().iter()
+                .fold(
+                    format!("resc/workflows/{}/{}", &self.name, &stage.name),
+                    |key, v| format!("{}/${{{}}}", key, v),
+                );
+            for dep in &stage.after {
+                let dep_stage = self.stage(dep).unwrap();
+                let join = if stage.after.len() > 1 {
+                    Some(Join {
+                        key: Pattern::from(join_key.as_str()),
+                        member: Pattern::from(dep.as_str()),
+                        expect: stage.after.iter().map(|s| Pattern::from(s.as_str())).collect(),
+                        ttl: self.join_ttl,
+                    })
+                } else {
+                    None
+                };
+                rules.push(Rule {
+                    name: format!("{}: {} -> {}", &self.name, dep, &stage.name),
+                    on_regex: dep_stage.task.to_regex().unwrap(),
+                    fetchers: Vec::new(),
+                    makers: Makers::Single(maker.clone()),
+                    debounce: None,
+                    join,
+                });
+            }
+        }
+        rules
+    }
+
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn workflow(stages: &str) -> WorkflowConf {
+        let hjson = format!(r#"{{ name: "wf", input_queue: "events", stages: [{}] }}"#, stages);
+        deser_hjson::from_str(&hjson).unwrap()
+    }
+
+    fn invalidity(wf: &WorkflowConf) -> String {
+        match wf.validate() {
+            Err(ConfError::InvalidWorkflow(_, reason)) => reason,
+            other => panic!("unexpected validation result: {:?}", other),
+        }
+    }
+
+    #[test]
+    fn valid_workflow() {
+        let wf = workflow(r#"
+            { name: "acq", task: "acq/${p}/${q}" }
+            { name: "trt", task: "trt/${p}/${q}", queue: "trt", after: ["acq"] }
+            { name: "publish", task: "publish/${p}", queue: "publish", after: ["trt"] }
+        "#);
+        wf.validate().unwrap();
+        let rules = wf.compile();
+        assert_eq!(rules.len(), 2);
+    }
+
+    #[test]
+    fn unknown_dependency() {
+        let wf = workflow(r#"
+            { name: "acq", task: "acq/${p}" }
+            { name: "trt", task: "trt/${p}", queue: "trt", after: ["acqq"] }
+        "#);
+        assert!(invalidity(&wf).contains("unknown stage \"acqq\""));
+    }
+
+    #[test]
+    fn cycle() {
+        let wf = workflow(r#"
+            { name: "acq", task: "acq/${p}" }
+            { name: "a", task: "a/${p}", queue: "a", after: ["acq", "b"] }
+            { name: "b", task: "b/${p}", queue: "b", after: ["a"] }
+        "#);
+        assert!(invalidity(&wf).starts_with("cycle"));
+    }
+
+    #[test]
+    fn missing_variable() {
+        let wf = workflow(r#"
+            { name: "acq", task: "acq/${p}" }
+            { name: "trt", task: "trt/${p}/${q}", queue: "trt", after: ["acq"] }
+        "#);
+        assert!(invalidity(&wf).contains("variable \"q\""));
+    }
+
+    #[test]
+    fn overlapping_tasks() {
+        let wf = workflow(r#"
+            { name: "acq", task: "acq/${p}" }
+            { name: "trt", task: "acq/${p}/trt", queue: "trt", after: ["acq"] }
+        "#);
+        assert!(invalidity(&wf).contains("can't be told apart"));
+    }
+}